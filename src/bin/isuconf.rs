@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use isuconf::commands::pull::{pull, PullOpt};
 use isuconf::commands::push::{push, PushOpt};
 use isuconf::commands::ssh::{ssh, SshOpt};
@@ -21,10 +21,17 @@ enum Opt {
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt: Opt = Opt::from_args();
-    match opt {
-        Opt::Pull(opt) => pull(opt).await,
-        Opt::Push(opt) => push(opt).await,
-        Opt::Ssh(opt) => ssh(opt).await,
-        Opt::SshConfig(opt) => ssh_config(opt).await,
+    let command = async {
+        match opt {
+            Opt::Pull(opt) => pull(opt).await,
+            Opt::Push(opt) => push(opt).await,
+            Opt::Ssh(opt) => ssh(opt).await,
+            Opt::SshConfig(opt) => ssh_config(opt).await,
+        }
+    };
+    // Dropping the command on Ctrl-C closes the connections and removes the control directory.
+    tokio::select! {
+        result = command => result,
+        _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted.")),
     }
 }
@@ -1,6 +1,9 @@
 pub use crate::common::*;
 
-pub use self::{local::*, remote::*};
+pub use self::{local::*, memory::*, remote::*, store::*};
 
 pub mod local;
+pub mod memory;
 pub mod remote;
+pub mod shell;
+pub mod store;
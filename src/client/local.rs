@@ -1,9 +1,19 @@
-use crate::client::join_path;
-use crate::config::{LocalConfig, TargetConfig};
-use anyhow::Result;
+use crate::client::{
+    join_path, local_target_path, match_glob, sha256_hex, ConfigStore, FileEntry, FileMetadata,
+    GlobMatch,
+};
+use crate::config::{LocalConfig, RemoteConfig, TargetConfig};
+use anyhow::{anyhow, Context, Result};
 use async_recursion::async_recursion;
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::sync::Mutex;
 
 #[async_recursion]
 async fn file_paths_in_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
@@ -12,7 +22,8 @@ async fn file_paths_in_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
         let mut dir = fs::read_dir(dir).await?;
         while let Some(entry) = dir.next_entry().await? {
             let path = entry.path();
-            if path.is_dir() {
+            // Symlinks to directories are listed as files, as `find` does.
+            if entry.file_type().await?.is_dir() {
                 file_paths.append(&mut file_paths_in_dirs(&path).await?);
             } else {
                 file_paths.push(path);
@@ -22,42 +33,182 @@ async fn file_paths_in_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(file_paths)
 }
 
+async fn remove_symlink(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_symlink() => Ok(fs::remove_file(path).await?),
+        _ => Ok(()),
+    }
+}
+
+/// File under `config_root_path` that records the metadata of the local files.
+const MANIFEST_FILE_NAME: &str = ".isuconf-metadata.yaml";
+
+#[derive(Serialize, Deserialize, Default)]
+struct ManifestEntry {
+    /// Octal permission bits, such as `0644`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+}
+
+impl ManifestEntry {
+    fn metadata(&self) -> Result<FileMetadata> {
+        let mode = self
+            .mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .map_err(|_| anyhow!("Invalid mode in metadata manifest. (mode={})", mode))
+            })
+            .transpose()?;
+        Ok(FileMetadata {
+            mode,
+            owner: self.owner.clone(),
+            group: self.group.clone(),
+        })
+    }
+}
+
+impl From<&FileMetadata> for ManifestEntry {
+    fn from(metadata: &FileMetadata) -> Self {
+        ManifestEntry {
+            mode: metadata.mode.map(|mode| format!("{:04o}", mode)),
+            owner: metadata.owner.clone(),
+            group: metadata.group.clone(),
+        }
+    }
+}
+
+/// Metadata of the local files keyed by their path relative to `config_root_path`. The local
+/// files are owned by the local user, so the metadata of the remote files is kept here.
+type Manifest = BTreeMap<String, ManifestEntry>;
+
+/// The manifest and whether it has changes not written to the file yet.
+struct ManifestState {
+    entries: Manifest,
+    is_changed: bool,
+}
+
 pub struct LocalConfigClient {
     config: LocalConfig,
+    remote: RemoteConfig,
+    manifest: Mutex<ManifestState>,
 }
 
 impl LocalConfigClient {
-    pub fn new(config: &LocalConfig) -> Self {
-        LocalConfigClient {
+    pub fn new(config: &LocalConfig, remote: &RemoteConfig) -> Result<Self> {
+        let manifest_path = Path::new(&config.config_root_path).join(MANIFEST_FILE_NAME);
+        let entries = if manifest_path.exists() {
+            let manifest = std::fs::read_to_string(&manifest_path)?;
+            serde_yaml::from_str(&manifest)
+                .with_context(|| format!("Invalid metadata manifest. (path={:?})", manifest_path))?
+        } else {
+            Manifest::new()
+        };
+        Ok(LocalConfigClient {
             config: config.clone(),
+            remote: remote.clone(),
+            manifest: Mutex::new(ManifestState {
+                entries,
+                is_changed: false,
+            }),
+        })
+    }
+
+    /// Key of a file in the manifest. A path that is not UTF-8 is recorded with its bytes
+    /// escaped after a leading `/`, which a path relative to `config_root_path` never starts
+    /// with, so that every path has its own key.
+    fn manifest_key(&self, path: &Path) -> Result<String> {
+        let path = path.strip_prefix(&self.config.config_root_path)?;
+        Ok(match path.to_str() {
+            Some(path) => path.to_owned(),
+            None => format!("/{}", path.as_os_str().as_bytes().escape_ascii()),
+        })
+    }
+
+    async fn metadata(&self, path: &Path) -> Result<FileMetadata> {
+        match self
+            .manifest
+            .lock()
+            .await
+            .entries
+            .get(&self.manifest_key(path)?)
+        {
+            Some(entry) => entry.metadata(),
+            None => Ok(FileMetadata::default()),
         }
     }
 
-    fn parent_path(&self, server_name: &str, target: &TargetConfig) -> PathBuf {
-        let path = Path::new(&self.config.config_root_path);
-        if !target.shared {
-            return path.join(server_name);
+    async fn forget_metadata(&self, path: &Path) -> Result<()> {
+        let mut manifest = self.manifest.lock().await;
+        if manifest.entries.remove(&self.manifest_key(path)?).is_some() {
+            manifest.is_changed = true;
         }
-        path.to_owned()
+        Ok(())
+    }
+
+    async fn record_metadata(&self, path: &Path, metadata: &FileMetadata) -> Result<()> {
+        if metadata.is_empty() {
+            return Ok(());
+        }
+        let key = self.manifest_key(path)?;
+        let mut manifest = self.manifest.lock().await;
+        let mut recorded = match manifest.entries.get(&key) {
+            Some(entry) => entry.metadata()?,
+            None => FileMetadata::default(),
+        };
+        recorded.merge(metadata);
+        manifest.entries.insert(key, ManifestEntry::from(&recorded));
+        manifest.is_changed = true;
+        Ok(())
+    }
+
+    /// Metadata of the regular file at `path`, following a symlink only when it resolves to a
+    /// file under `config_root_path`.
+    async fn resolve(&self, path: &Path, is_symlink: bool) -> Option<std::fs::Metadata> {
+        if is_symlink {
+            let root = fs::canonicalize(&self.config.config_root_path).await.ok()?;
+            if !fs::canonicalize(path).await.ok()?.starts_with(root) {
+                return None;
+            }
+        }
+        fs::metadata(path)
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+    }
+
+    fn parent_path(&self, server_name: &str, target: &TargetConfig) -> PathBuf {
+        join_path(
+            Path::new(&self.config.config_root_path),
+            &target.local_dir(server_name),
+        )
     }
 
     fn path(&self, server_name: &str, target: &TargetConfig) -> Result<PathBuf> {
-        let target_path = Path::new(&target.path);
-        let path = self
-            .parent_path(server_name, target)
-            .join(if target_path.is_absolute() {
-                target_path.strip_prefix("/")?
-            } else {
-                target_path
-            });
-        Ok(path)
+        let user = if target.path == "~" || target.path.starts_with("~/") {
+            target.home_user(self.remote.server(server_name)?, &self.remote)
+        } else {
+            None
+        };
+        let target_path = local_target_path(&target.path, user);
+        Ok(join_path(
+            &self.parent_path(server_name, target),
+            &target_path,
+        ))
     }
+}
 
-    pub async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool> {
+#[async_trait]
+impl ConfigStore for LocalConfigClient {
+    async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool> {
         Ok(self.path(server_name, target)?.exists())
     }
 
-    pub async fn exists_relative_path(
+    async fn exists_relative_path(
         &self,
         server: &str,
         target: &TargetConfig,
@@ -66,7 +217,7 @@ impl LocalConfigClient {
         Ok(self.real_path(server, target, relative_path)?.exists())
     }
 
-    pub async fn file_relative_paths(
+    async fn file_relative_paths(
         &self,
         server_name: &str,
         target: &TargetConfig,
@@ -82,14 +233,93 @@ impl LocalConfigClient {
         } else {
             vec![]
         };
+        let filter = target.path_filter()?;
         let paths: Result<Vec<_>, _> = paths
             .iter()
             .map(|p| p.strip_prefix(&path).map(|path| path.to_owned()))
+            .filter(|p| p.as_ref().map_or(true, |p| filter.is_match(p)))
             .collect();
         Ok(paths?)
     }
 
-    pub fn real_path(
+    async fn expand(&self, server_name: &str, target: &TargetConfig) -> Result<Vec<GlobMatch>> {
+        let base = TargetConfig {
+            path: target.glob_base().to_owned(),
+            ..target.to_owned()
+        };
+        let path = self.path(server_name, &base)?;
+        if !path.is_dir() {
+            return Ok(vec![]);
+        }
+        let paths: Result<Vec<_>, _> = file_paths_in_dirs(&path)
+            .await?
+            .iter()
+            .map(|p| p.strip_prefix(&path).map(|path| path.to_owned()))
+            .collect();
+        match_glob(target, &paths?)
+    }
+
+    async fn inventory(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        max_file_size: u64,
+    ) -> Result<Vec<FileEntry>> {
+        let mut entries = vec![];
+        for relative_path in self.file_relative_paths(server_name, target).await? {
+            let path = self.real_path(server_name, target, &relative_path)?;
+            let metadata = fs::symlink_metadata(&path).await?;
+            if metadata.is_symlink() && !target.follow_symlinks {
+                entries.push(FileEntry {
+                    relative_path,
+                    size: metadata.len(),
+                    metadata: FileMetadata::default(),
+                    mtime: Some(metadata.mtime()),
+                    hash: None,
+                    link_target: Some(fs::read_link(&path).await?),
+                    is_readable: true,
+                });
+                continue;
+            }
+            // A followed symlink must resolve to a file in the mirror, since a link pulled as is
+            // points into the remote filesystem, not the local one.
+            let metadata = match self.resolve(&path, metadata.is_symlink()).await {
+                Some(metadata) => metadata,
+                None => {
+                    entries.push(FileEntry {
+                        relative_path,
+                        size: 0,
+                        metadata: FileMetadata::default(),
+                        mtime: None,
+                        hash: None,
+                        link_target: None,
+                        is_readable: false,
+                    });
+                    continue;
+                }
+            };
+            let (hash, is_readable) = if metadata.len() > max_file_size {
+                (None, true)
+            } else {
+                match fs::read(&path).await {
+                    Ok(config_bytes) => (Some(sha256_hex(&config_bytes)), true),
+                    Err(_) => (None, false),
+                }
+            };
+            entries.push(FileEntry {
+                relative_path,
+                size: metadata.len(),
+                metadata: self.metadata(&path).await?,
+                mtime: Some(metadata.mtime()),
+                hash,
+                link_target: None,
+                is_readable,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn real_path(
         &self,
         server: &str,
         target: &TargetConfig,
@@ -98,17 +328,24 @@ impl LocalConfigClient {
         Ok(join_path(&self.path(server, target)?, relative_path))
     }
 
-    pub async fn len(
+    fn display_path(
         &self,
         server: &str,
         target: &TargetConfig,
         relative_path: &Path,
-    ) -> Result<u64> {
+    ) -> Result<String> {
+        Ok(self
+            .real_path(server, target, relative_path)?
+            .display()
+            .to_string())
+    }
+
+    async fn len(&self, server: &str, target: &TargetConfig, relative_path: &Path) -> Result<u64> {
         let path = self.real_path(server, target, relative_path)?;
         Ok(fs::metadata(path).await?.len())
     }
 
-    pub async fn get(
+    async fn get(
         &self,
         server: &str,
         target: &TargetConfig,
@@ -118,18 +355,186 @@ impl LocalConfigClient {
         Ok(fs::read(path).await?)
     }
 
-    pub async fn create(
+    async fn create(
         &self,
         server: &str,
         target: &TargetConfig,
         relative_path: &Path,
         config_bytes: Vec<u8>,
+        metadata: &FileMetadata,
     ) -> Result<()> {
         let path = self.real_path(server, target, relative_path)?;
         if let Some(dir) = path.parent() {
             fs::create_dir_all(dir).await?;
         }
-        fs::write(path, config_bytes).await?;
+        // A symlink is never written through, so that nothing outside the mirror is changed.
+        remove_symlink(&path).await?;
+        fs::write(&path, config_bytes).await?;
+        self.record_metadata(&path, metadata).await
+    }
+
+    async fn create_link(
+        &self,
+        server: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        link_target: &Path,
+    ) -> Result<()> {
+        let path = self.real_path(server, target, relative_path)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        if fs::symlink_metadata(&path).await.is_ok() {
+            fs::remove_file(&path).await?;
+        }
+        fs::symlink(link_target, &path).await?;
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        server: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<()> {
+        let path = self.real_path(server, target, relative_path)?;
+        fs::remove_file(&path).await?;
+        self.forget_metadata(&path).await
+    }
+
+    async fn set_metadata(
+        &self,
+        server: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        let path = self.real_path(server, target, relative_path)?;
+        self.record_metadata(&path, metadata).await
+    }
+
+    /// Write the manifest to a temporary file and rename it into place, so that it is never left
+    /// partially written.
+    async fn flush(&self) -> Result<()> {
+        let mut manifest = self.manifest.lock().await;
+        if !manifest.is_changed {
+            return Ok(());
+        }
+        let root = Path::new(&self.config.config_root_path);
+        fs::create_dir_all(root).await?;
+        let mut file = tempfile::Builder::new()
+            .prefix(".isuconf-metadata")
+            .tempfile_in(root)?;
+        file.write_all(serde_yaml::to_string(&manifest.entries)?.as_bytes())?;
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+        file.persist(root.join(MANIFEST_FILE_NAME))?;
+        manifest.is_changed = false;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn local_client(root: &Path) -> LocalConfigClient {
+        let config = LocalConfig {
+            config_root_path: root.to_str().unwrap().to_owned(),
+        };
+        let remote = serde_yaml::from_str("servers:\n  - host: is1\n").unwrap();
+        LocalConfigClient::new(&config, &remote).unwrap()
+    }
+
+    fn target() -> TargetConfig {
+        serde_yaml::from_str("path: /etc/app").unwrap()
+    }
+
+    #[tokio::test]
+    async fn manifest_keeps_non_utf8_names_apart_and_is_written_on_flush() {
+        let root = tempfile::tempdir().unwrap();
+        let client = local_client(root.path());
+        let target = target();
+        for (name, mode) in [
+            (&b"a\xff"[..], 0o600),
+            (b"a\xfe", 0o640),
+            (b"a\\xff", 0o644),
+        ] {
+            let metadata = FileMetadata {
+                mode: Some(mode),
+                ..FileMetadata::default()
+            };
+            let path = Path::new(OsStr::from_bytes(name));
+            client
+                .create("is1", &target, path, b"x".to_vec(), &metadata)
+                .await
+                .unwrap();
+        }
+        let manifest_path = root.path().join(MANIFEST_FILE_NAME);
+        assert!(!manifest_path.exists());
+
+        client.flush().await.unwrap();
+        let client = local_client(root.path());
+        let entries = client.inventory("is1", &target, 1024).await.unwrap();
+        let mut modes = entries
+            .iter()
+            .map(|entry| (entry.relative_path.clone(), entry.metadata.mode))
+            .collect::<Vec<_>>();
+        modes.sort();
+        assert_eq!(
+            modes,
+            vec![
+                (PathBuf::from("a\\xff"), Some(0o644)),
+                (PathBuf::from(OsStr::from_bytes(b"a\xfe")), Some(0o640)),
+                (PathBuf::from(OsStr::from_bytes(b"a\xff")), Some(0o600)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn followed_symlinks_never_reach_outside_the_mirror() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let outside_file = outside.path().join("default");
+        std::fs::write(&outside_file, b"live").unwrap();
+        let client = local_client(root.path());
+        let target: TargetConfig =
+            serde_yaml::from_str("path: /etc/app\nfollow_symlinks: true").unwrap();
+        let dir = client.real_path("is1", &target, Path::new("")).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("inside"), b"a").unwrap();
+        std::os::unix::fs::symlink("inside", dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside_file, dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("dangling")).unwrap();
+
+        let entries = client.inventory("is1", &target, 1024).await.unwrap();
+        let mut readable = entries
+            .iter()
+            .map(|entry| (entry.relative_path.to_str().unwrap(), entry.is_readable))
+            .collect::<Vec<_>>();
+        readable.sort();
+        assert_eq!(
+            readable,
+            vec![
+                ("dangling", false),
+                ("inside", true),
+                ("link", true),
+                ("outside", false),
+            ]
+        );
+
+        client
+            .create(
+                "is1",
+                &target,
+                Path::new("outside"),
+                b"pulled".to_vec(),
+                &FileMetadata::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&outside_file).unwrap(), b"live");
+        assert_eq!(std::fs::read(dir.join("outside")).unwrap(), b"pulled");
+    }
+}
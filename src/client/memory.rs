@@ -0,0 +1,326 @@
+use crate::client::{
+    join_path, match_glob, sha256_hex, ConfigStore, FileEntry, FileMetadata, GlobMatch,
+};
+use crate::config::TargetConfig;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Clone, Default)]
+struct MemoryFile {
+    config_bytes: Vec<u8>,
+    metadata: FileMetadata,
+    link_target: Option<PathBuf>,
+}
+
+type Files = BTreeMap<(String, PathBuf), MemoryFile>;
+
+/// The regular file at `path`, following symlinks. A relative link target is resolved against
+/// the directory of the link.
+fn resolve<'a>(files: &'a Files, server_name: &str, path: &Path) -> Option<&'a MemoryFile> {
+    let mut path = path.to_owned();
+    // Give up on a symlink loop, as the kernel does.
+    for _ in 0..40 {
+        let file = files.get(&(server_name.to_owned(), path.clone()))?;
+        match &file.link_target {
+            Some(link_target) => {
+                let mut resolved = path.parent()?.to_owned();
+                for component in link_target.components() {
+                    match component {
+                        Component::ParentDir => {
+                            resolved.pop();
+                        }
+                        Component::CurDir => {}
+                        component => resolved.push(component),
+                    }
+                }
+                path = resolved;
+            }
+            None => return Some(file),
+        }
+    }
+    None
+}
+
+/// In-memory config store, keyed by server name and real path.
+#[derive(Default)]
+pub struct MemoryConfigClient {
+    files: Mutex<Files>,
+}
+
+impl MemoryConfigClient {
+    pub fn new() -> Self {
+        MemoryConfigClient::default()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<Files>> {
+        self.files
+            .lock()
+            .map_err(|_| anyhow!("Memory store is poisoned."))
+    }
+
+    pub fn insert(&self, server_name: &str, path: impl Into<PathBuf>, config_bytes: Vec<u8>) {
+        if let Ok(mut files) = self.lock() {
+            files.insert(
+                (server_name.to_owned(), path.into()),
+                MemoryFile {
+                    config_bytes,
+                    ..MemoryFile::default()
+                },
+            );
+        }
+    }
+
+    pub fn insert_link(&self, server_name: &str, path: impl Into<PathBuf>, link_target: &str) {
+        if let Ok(mut files) = self.lock() {
+            files.insert(
+                (server_name.to_owned(), path.into()),
+                MemoryFile {
+                    link_target: Some(PathBuf::from(link_target)),
+                    ..MemoryFile::default()
+                },
+            );
+        }
+    }
+
+    pub fn file(&self, server_name: &str, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.lock()
+            .ok()?
+            .get(&(server_name.to_owned(), path.as_ref().to_owned()))
+            .map(|file| file.config_bytes.clone())
+    }
+
+    pub fn link_target(&self, server_name: &str, path: impl AsRef<Path>) -> Option<PathBuf> {
+        self.lock()
+            .ok()?
+            .get(&(server_name.to_owned(), path.as_ref().to_owned()))?
+            .link_target
+            .clone()
+    }
+
+    pub fn metadata(&self, server_name: &str, path: impl AsRef<Path>) -> Option<FileMetadata> {
+        self.lock()
+            .ok()?
+            .get(&(server_name.to_owned(), path.as_ref().to_owned()))
+            .map(|file| file.metadata.clone())
+    }
+
+    fn file_paths(&self, server_name: &str, target: &TargetConfig) -> Result<Vec<PathBuf>> {
+        let path = Path::new(&target.path);
+        Ok(self
+            .lock()?
+            .keys()
+            .filter(|(server, file_path)| server == server_name && file_path.starts_with(path))
+            .map(|(_, file_path)| file_path.to_owned())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ConfigStore for MemoryConfigClient {
+    async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool> {
+        Ok(!self.file_paths(server_name, target)?.is_empty())
+    }
+
+    async fn exists_relative_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<bool> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        Ok(self.lock()?.contains_key(&(server_name.to_owned(), path)))
+    }
+
+    async fn file_relative_paths(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+    ) -> Result<Vec<PathBuf>> {
+        let filter = target.path_filter()?;
+        let paths: Result<Vec<_>, _> = self
+            .file_paths(server_name, target)?
+            .iter()
+            .map(|p| p.strip_prefix(&target.path).map(|path| path.to_owned()))
+            .filter(|p| p.as_ref().map_or(true, |p| filter.is_match(p)))
+            .collect();
+        Ok(paths?)
+    }
+
+    async fn expand(&self, server_name: &str, target: &TargetConfig) -> Result<Vec<GlobMatch>> {
+        let base = Path::new(target.glob_base());
+        let paths: Vec<_> = self
+            .lock()?
+            .keys()
+            .filter(|(server, _)| server == server_name)
+            .filter_map(|(_, path)| path.strip_prefix(base).ok())
+            .map(Path::to_owned)
+            .collect();
+        match_glob(target, &paths)
+    }
+
+    async fn inventory(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        max_file_size: u64,
+    ) -> Result<Vec<FileEntry>> {
+        let paths = self.file_relative_paths(server_name, target).await?;
+        let files = self.lock()?;
+        let mut entries = vec![];
+        for relative_path in paths {
+            let path = self.real_path(server_name, target, &relative_path)?;
+            let file = files
+                .get(&(server_name.to_owned(), path.clone()))
+                .ok_or_else(|| {
+                    anyhow!("Not found file. (server={}, path={:?})", server_name, path)
+                })?;
+            let file = match &file.link_target {
+                Some(_) if target.follow_symlinks => match resolve(&files, server_name, &path) {
+                    Some(file) => file,
+                    None => {
+                        entries.push(FileEntry {
+                            relative_path,
+                            size: 0,
+                            metadata: FileMetadata::default(),
+                            mtime: None,
+                            hash: None,
+                            link_target: None,
+                            is_readable: false,
+                        });
+                        continue;
+                    }
+                },
+                _ => file,
+            };
+            entries.push(match &file.link_target {
+                Some(link_target) => FileEntry {
+                    relative_path,
+                    size: link_target.as_os_str().len() as u64,
+                    metadata: FileMetadata::default(),
+                    mtime: None,
+                    hash: None,
+                    link_target: Some(link_target.clone()),
+                    is_readable: true,
+                },
+                None => FileEntry {
+                    relative_path,
+                    size: file.config_bytes.len() as u64,
+                    metadata: file.metadata.clone(),
+                    mtime: None,
+                    hash: Some(&file.config_bytes)
+                        .filter(|bytes| bytes.len() as u64 <= max_file_size)
+                        .map(|bytes| sha256_hex(bytes)),
+                    link_target: None,
+                    is_readable: true,
+                },
+            });
+        }
+        Ok(entries)
+    }
+
+    fn real_path(
+        &self,
+        _server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<PathBuf> {
+        Ok(join_path(Path::new(&target.path), relative_path))
+    }
+
+    fn display_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<String> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        Ok(format!("{}:{}", server_name, path.display()))
+    }
+
+    async fn len(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<u64> {
+        Ok(self.get(server_name, target, relative_path).await?.len() as u64)
+    }
+
+    async fn get(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<Vec<u8>> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        let files = self.lock()?;
+        resolve(&files, server_name, &path)
+            .map(|file| file.config_bytes.clone())
+            .ok_or_else(|| anyhow!("Not found file. (server={}, path={:?})", server_name, path))
+    }
+
+    async fn create(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        config_bytes: Vec<u8>,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        let mut files = self.lock()?;
+        let file = files.entry((server_name.to_owned(), path)).or_default();
+        file.config_bytes = config_bytes;
+        file.metadata.merge(metadata);
+        file.link_target = None;
+        Ok(())
+    }
+
+    async fn create_link(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        link_target: &Path,
+    ) -> Result<()> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        self.lock()?.insert(
+            (server_name.to_owned(), path),
+            MemoryFile {
+                link_target: Some(link_target.to_owned()),
+                ..MemoryFile::default()
+            },
+        );
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<()> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        self.lock()?.remove(&(server_name.to_owned(), path));
+        Ok(())
+    }
+
+    async fn set_metadata(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        self.lock()?
+            .get_mut(&(server_name.to_owned(), path.clone()))
+            .ok_or_else(|| anyhow!("Not found file. (server={}, path={:?})", server_name, path))?
+            .metadata
+            .merge(metadata);
+        Ok(())
+    }
+}
@@ -1,49 +1,580 @@
-use crate::client::{convert_to_string, join_path};
-use crate::config::{RemoteConfig, TargetConfig};
+use crate::client::{join_path, shell, ConfigStore, FileEntry, FileMetadata, GlobMatch};
+use crate::config::{Escalation, KnownHostsPolicy, RemoteConfig, ServerConfig, TargetConfig};
 use anyhow::{anyhow, Context, Result};
-use chrono::Local;
+use async_trait::async_trait;
+use futures::StreamExt;
 use itertools::Itertools;
 use openssh::{KnownHosts, Session, SessionBuilder};
 use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::process::Stdio;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempDir};
+use tokio::io::AsyncWriteExt;
 use tokio::time::timeout;
 
+/// Prints the target root, then the path of every file, each terminated by NUL. Prints nothing
+/// when the target does not exist.
+const LIST_SCRIPT: &str = r#"[ -e "$1" ] || exit 0
+printf '%s\0' "$1"
+find "$1" \( -type f -o -type l \) -print0
+"#;
+
+/// Prints `1` when `$1` exists, including a dangling symlink.
+const EXISTS_SCRIPT: &str = r#"if [ -e "$1" ] || [ -L "$1" ]; then printf 1; fi"#;
+
+/// Prints every existing path matched by the glob `$1` and `d` for a directory or `f` otherwise,
+/// each terminated by NUL. `$1` is left unquoted for pathname expansion only, so nothing else in
+/// it is interpreted.
+const EXPAND_SCRIPT: &str = r#"IFS=
+for f in $1; do
+  [ -e "$f" ] || [ -L "$f" ] || continue
+  if [ -d "$f" ]; then t=d; else t=f; fi
+  printf '%s\0%s\0' "$f" "$t"
+done
+"#;
+
+/// Prints the target root, then a record of every file, each field terminated by NUL. A file is
+/// recorded as its path and `f size mode owner group mtime sha256`, where the hash is `-` when
+/// the size exceeds `$3`. Unless `$2` is set, a symlink is recorded as its path,
+/// `l size mode owner group mtime` and its target. A file that cannot be read is recorded as its
+/// path and `e`. With `$4` set, only the NUL terminated paths read from stdin are recorded,
+/// instead of every file under the target. Prints nothing when the target does not exist.
+const INVENTORY_SCRIPT: &str = r#"[ -e "$1" ] || [ -L "$1" ] || exit 0
+printf '%s\0' "$1"
+record='
+follow=$1
+max=$2
+shift 2
+for f do
+  if [ -L "$f" ]; then
+    if [ -z "$follow" ]; then
+      m=$(stat -c "%s %a %U %G %Y" -- "$f" 2>/dev/null) || { printf "%s\0e\0" "$f"; continue; }
+      printf "%s\0l %s\0%s\0" "$f" "$m" "$(readlink -- "$f")"
+      continue
+    fi
+    # Dangling symlinks and symlinks to directories have no content to transfer.
+    [ -f "$f" ] || continue
+  fi
+  m=$(stat -L -c "%s %a %U %G %Y" -- "$f" 2>/dev/null) || { printf "%s\0e\0" "$f"; continue; }
+  if [ "${m%% *}" -gt "$max" ]; then
+    printf "%s\0f %s -\0" "$f" "$m"
+    continue
+  fi
+  h=$(sha256sum 2>/dev/null < "$f") || { printf "%s\0e\0" "$f"; continue; }
+  printf "%s\0f %s %s\0" "$f" "$m" "${h%% *}"
+done'
+if [ -n "$4" ]; then
+  xargs -0 sh -c "$record" sh "$2" "$3"
+else
+  find "$1" \( -type f -o -type l \) -exec sh -c "$record" sh "$2" "$3" {} +
+fi
+"#;
+
+/// Writes stdin to a temporary file next to the destination and renames it into place, so the
+/// destination is never left truncated. An existing file keeps its owner and mode unless the
+/// chown spec and mode are given as `$2` and `$3`, and the write fails when its owner cannot be
+/// kept. A symlink is written through to the file it points to when `$4` is set, and replaced
+/// otherwise.
+const WRITE_SCRIPT: &str = r#"set -e
+path=$1
+if [ -n "$4" ] && [ -L "$path" ]; then
+  path=$(readlink -f -- "$path")
+fi
+dir=$(dirname -- "$path")
+mkdir -p -- "$dir"
+tmp=$(mktemp -- "$dir/.isuconf.XXXXXX")
+trap 'rm -f -- "$tmp"' EXIT
+cat > "$tmp"
+if [ -e "$path" ] && [ ! -L "$path" ]; then
+  case $2 in
+    ?*:?*) ;;
+    *)
+      chown --reference="$path" -- "$tmp" 2>/dev/null || {
+        printf 'Failed to keep the owner of %s. Set owner and group of the target.\n' "$path" >&2
+        exit 1
+      } ;;
+  esac
+  chmod --reference="$path" -- "$tmp"
+else
+  chmod "$(printf '%o' $((0666 & ~$(umask))))" -- "$tmp"
+fi
+[ -z "$2" ] || chown -- "$2" "$tmp"
+[ -z "$3" ] || chmod -- "$3" "$tmp"
+mv -Tf -- "$tmp" "$path"
+trap - EXIT
+"#;
+
+/// Replaces `$1` with a symlink to `$2` by renaming a new symlink into place.
+const LINK_SCRIPT: &str = r#"set -e
+dir=$(dirname -- "$1")
+mkdir -p -- "$dir"
+tmp=$(mktemp -u -- "$dir/.isuconf.XXXXXX")
+ln -s -- "$2" "$tmp"
+mv -Tf -- "$tmp" "$1"
+"#;
+
+/// Applies the chown spec `$2` and the mode `$3` to `$1`, skipping the empty ones.
+const METADATA_SCRIPT: &str = r#"set -e
+[ -z "$2" ] || chown -- "$2" "$1"
+[ -z "$3" ] || chmod -- "$3" "$1"
+"#;
+
+/// Arguments of `WRITE_SCRIPT` and `METADATA_SCRIPT` for the metadata: the chown spec and the
+/// octal mode, empty when not set.
+fn metadata_args(metadata: &FileMetadata) -> (String, String) {
+    let chown = match (&metadata.owner, &metadata.group) {
+        (Some(owner), Some(group)) => format!("{}:{}", owner, group),
+        (Some(owner), None) => owner.to_owned(),
+        (None, Some(group)) => format!(":{}", group),
+        (None, None) => String::new(),
+    };
+    let mode = metadata
+        .mode
+        .map(|mode| format!("{:o}", mode))
+        .unwrap_or_default();
+    (chown, mode)
+}
+
+/// Split NUL terminated output into the target root and the remaining fields.
+fn split_fields(output: &[u8]) -> Option<(&Path, impl Iterator<Item = &[u8]>)> {
+    let mut fields = output.split(|&b| b == b'\0');
+    match fields.next() {
+        Some(root) if !root.is_empty() => Some((Path::new(OsStr::from_bytes(root)), fields)),
+        _ => None,
+    }
+}
+
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    Path::new(OsStr::from_bytes(bytes)).to_owned()
+}
+
+fn relative_path(root: &Path, path: &[u8]) -> Result<PathBuf> {
+    Ok(bytes_to_path(path).strip_prefix(root)?.to_owned())
+}
+
+fn parse_list(output: &[u8]) -> Result<Vec<PathBuf>> {
+    let (root, fields) = match split_fields(output) {
+        Some(split) => split,
+        None => return Ok(vec![]),
+    };
+    fields
+        .filter(|path| !path.is_empty())
+        .map(|path| relative_path(root, path))
+        .collect()
+}
+
+fn parse_inventory(output: &[u8]) -> Result<Vec<FileEntry>> {
+    let (root, mut fields) = match split_fields(output) {
+        Some(split) => split,
+        None => return Ok(vec![]),
+    };
+
+    let mut entries = vec![];
+    while let (Some(path), Some(metadata)) = (fields.next(), fields.next()) {
+        let metadata = String::from_utf8_lossy(metadata);
+        let invalid = || anyhow!("Failed to parse inventory. (line={})", metadata);
+        let mut values = metadata.split(' ');
+        let kind = values.next().ok_or_else(invalid)?;
+        if kind == "e" {
+            entries.push(FileEntry {
+                relative_path: relative_path(root, path)?,
+                size: 0,
+                metadata: FileMetadata::default(),
+                mtime: None,
+                hash: None,
+                link_target: None,
+                is_readable: false,
+            });
+            continue;
+        }
+        let (size, mode, owner, group, mtime) =
+            (&mut values).take(5).collect_tuple().ok_or_else(invalid)?;
+        let size = size.parse().map_err(|_| invalid())?;
+        let mtime = Some(mtime.parse().map_err(|_| invalid())?);
+        let entry = match kind {
+            "f" => FileEntry {
+                relative_path: relative_path(root, path)?,
+                size,
+                metadata: FileMetadata {
+                    mode: Some(u32::from_str_radix(mode, 8).map_err(|_| invalid())?),
+                    owner: Some(owner.to_owned()),
+                    group: Some(group.to_owned()),
+                },
+                mtime,
+                hash: Some(values.next().ok_or_else(invalid)?)
+                    .filter(|hash| *hash != "-")
+                    .map(str::to_owned),
+                link_target: None,
+                is_readable: true,
+            },
+            // The metadata of a symlink is not managed, since chmod applies to its target.
+            "l" => FileEntry {
+                relative_path: relative_path(root, path)?,
+                size,
+                metadata: FileMetadata::default(),
+                mtime,
+                hash: None,
+                link_target: Some(fields.next().map(bytes_to_path).ok_or_else(invalid)?),
+                is_readable: true,
+            },
+            _ => return Err(invalid()),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Parse the output of `EXPAND_SCRIPT` into paths relative to `root`.
+fn parse_glob_matches(root: &Path, output: &[u8]) -> Result<Vec<GlobMatch>> {
+    let mut fields = output.split(|&b| b == b'\0');
+    let mut matches = vec![];
+    while let (Some(path), Some(kind)) = (fields.next(), fields.next()) {
+        // `.*` also matches `.` and `..`, which lead out of the matched directory.
+        let matched = path.get(root.as_os_str().len()..).unwrap_or_default();
+        if matched
+            .split(|&b| b == b'/')
+            .any(|name| name == b"." || name == b"..")
+        {
+            continue;
+        }
+        matches.push(GlobMatch {
+            relative_path: relative_path(root, path)?,
+            is_dir: kind == b"d",
+        });
+    }
+    Ok(matches)
+}
+
+/// Messages of escalation commands that need a password, which cannot be given without a
+/// terminal, as the start of a line of stderr and a part of the line.
+const PASSWORD_PROMPTS: &[(&str, &str)] = &[
+    ("[sudo] password for ", ""),
+    ("sudo: ", "password is required"),
+    ("sudo: ", "terminal is required"),
+    ("sudo: ", "no tty present"),
+    ("doas (", ") password:"),
+    ("doas: ", "password is required"),
+    ("doas: ", "tty is required"),
+    ("su: ", "must be run from a terminal"),
+];
+
+fn is_password_prompt(stderr: &str) -> bool {
+    stderr.lines().map(str::trim).any(|line| {
+        // The prompt of `su`.
+        line == "Password:"
+            || PASSWORD_PROMPTS
+                .iter()
+                .any(|(start, part)| line.starts_with(start) && line.contains(part))
+    })
+}
+
+/// User a remote command runs as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunAs<'a> {
+    Login,
+    Root,
+    User(&'a str),
+}
+
+impl<'a> RunAs<'a> {
+    fn of(target: &'a TargetConfig) -> Self {
+        match (&target.become_user, target.sudo) {
+            (Some(user), _) => RunAs::User(user),
+            (None, true) => RunAs::Root,
+            (None, false) => RunAs::Login,
+        }
+    }
+}
+
+/// Split a path starting with `~` or `~user` into the tilde prefix and the rest of the path.
+/// `~` is rewritten to `~{become_user}` when set, since the file belongs to that user.
+fn split_tilde(path: &Path, become_user: Option<&str>) -> Option<(String, PathBuf)> {
+    let bytes = path.as_os_str().as_bytes();
+    if !bytes.starts_with(b"~") {
+        return None;
+    }
+    let end = bytes.iter().position(|&b| b == b'/').unwrap_or(bytes.len());
+    let (tilde, rest) = bytes.split_at(end);
+    let tilde = match (tilde, become_user) {
+        (b"~", Some(user)) => format!("~{}", user),
+        _ => String::from_utf8_lossy(tilde).into_owned(),
+    };
+    let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+    Some((tilde, bytes_to_path(rest)))
+}
+
+/// Wrap a command line so that it runs as `user`, or root by default, with `escalation`.
+fn escalate(escalation: Escalation, command: &OsStr, user: Option<&str>) -> OsString {
+    let mut args = match escalation {
+        Escalation::Sudo => vec!["sudo"],
+        Escalation::SudoNonInteractive => vec!["sudo", "-n"],
+        Escalation::Doas => vec!["doas"],
+        Escalation::Template(template) => {
+            let template = template.replace(
+                "{user}",
+                &shell::quote(user.unwrap_or("root")).to_string_lossy(),
+            );
+            let mut escalated = OsString::new();
+            for (idx, part) in template.split("{command}").enumerate() {
+                if idx >= 1 {
+                    escalated.push(shell::quote(command));
+                }
+                escalated.push(part);
+            }
+            return escalated;
+        }
+    };
+    if let Some(user) = user {
+        args.extend(["-u", user]);
+    }
+    let mut args = args.into_iter().map(OsStr::new).collect_vec();
+    args.extend([OsStr::new("sh"), OsStr::new("-c"), command]);
+    shell::command_line(&args)
+}
+
+/// Outcome of connecting to a server.
+pub struct ServerConnection {
+    pub server_name: String,
+    pub elapsed: Duration,
+    pub error: Option<anyhow::Error>,
+}
+
 pub struct RemoteConfigClient {
     config: RemoteConfig,
     sessions: HashMap<String, Session>,
+    /// Home directories resolved on the servers, keyed by server name and `~` or `~user`.
+    homes: Mutex<HashMap<(String, String), PathBuf>>,
+    connections: Vec<ServerConnection>,
+    // Declared last so that it is removed after the sessions are dropped.
+    control_directory: Option<TempDir>,
+}
+
+/// Create a private directory for the control sockets of this invocation. It is placed under
+/// `control_directory`, or the user's runtime directory by default.
+fn create_control_directory(config: &RemoteConfig) -> Result<TempDir> {
+    let parent = match &config.control_directory {
+        Some(control_directory) => PathBuf::from(control_directory),
+        None => env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir),
+    };
+    fs::create_dir_all(&parent)
+        .with_context(|| format!("Failed to create control directory. (path={:?})", parent))?;
+    let dir = tempfile::Builder::new()
+        .prefix("isuconf-")
+        .tempdir_in(&parent)
+        .with_context(|| format!("Failed to create control directory. (path={:?})", parent))?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// Options that `SessionBuilder` cannot set, as an ssh config. ssh reads the same config for
+/// the jump host, so `ProxyJump` is scoped to the destination instead of making the jump host
+/// jump through itself. The user and system configs are included, since ssh skips them when a
+/// config file is given.
+fn ssh_config(config: &RemoteConfig, server: &ServerConfig) -> Result<Option<String>> {
+    let proxy_jump = server.proxy_jump(config);
+    if proxy_jump.is_none() && config.known_hosts_file.is_none() {
+        return Ok(None);
+    }
+    let mut lines = vec![];
+    if let Some(proxy_jump) = proxy_jump {
+        lines.push(format!("Host {}", server.destination(config)?));
+        lines.push(format!("  ProxyJump {}", proxy_jump));
+    }
+    lines.push("Host *".to_owned());
+    if let Some(known_hosts_file) = &config.known_hosts_file {
+        lines.push(format!("  UserKnownHostsFile \"{}\"", known_hosts_file));
+    }
+    lines.push("Include ~/.ssh/config".to_owned());
+    lines.push("Include /etc/ssh/ssh_config".to_owned());
+    Ok(Some(lines.into_iter().map(|line| line + "\n").collect()))
+}
+
+/// Write the ssh config to a temporary file.
+fn write_ssh_config(ssh_config: &str, dir: &Path) -> Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .prefix(".isuconf-ssh-config")
+        .tempfile_in(dir)?;
+    file.write_all(ssh_config.as_bytes())?;
+    file.flush()?;
+    Ok(file)
+}
+
+async fn connect(
+    config: &RemoteConfig,
+    server: &ServerConfig,
+    control_directory: &Path,
+) -> Result<Session> {
+    let destination = server.destination(config)?;
+    let mut builder = SessionBuilder::default();
+    if let Some(user) = server.user(config) {
+        builder.user(user.to_owned());
+    }
+    builder.known_hosts_check(match config.known_hosts {
+        KnownHostsPolicy::Strict => KnownHosts::Strict,
+        KnownHostsPolicy::Add => KnownHosts::Add,
+        KnownHostsPolicy::Accept => KnownHosts::Accept,
+    });
+    if let Some(identity) = server.identity(config) {
+        builder.keyfile(identity);
+    }
+    if let Some(port) = server.port(config) {
+        builder.port(port);
+    }
+    builder.control_directory(control_directory);
+    // The config is only read while establishing the master connection.
+    let ssh_config = ssh_config(config, server)?
+        .map(|ssh_config| write_ssh_config(&ssh_config, control_directory))
+        .transpose()?;
+    if let Some(ssh_config) = &ssh_config {
+        builder.config_file(ssh_config.path());
+    }
+    let session = timeout(
+        Duration::from_secs(config.timeout.unwrap_or(5)),
+        builder.connect(destination),
+    )
+    .await
+    .map_err(|_| anyhow!("Timeout connect to {}", destination))??;
+    Ok(session)
 }
 
 impl RemoteConfigClient {
-    pub async fn new(config: &RemoteConfig) -> Result<Self> {
+    /// Connect to the servers, up to `concurrency` at a time, starting the next one as soon as
+    /// any finishes. With `skip_unreachable`, servers that fail to connect are recorded in
+    /// `connections` instead of aborting.
+    pub async fn new(
+        config: &RemoteConfig,
+        concurrency: usize,
+        skip_unreachable: bool,
+    ) -> Result<Self> {
+        let control_directory = create_control_directory(config)?;
         let mut sessions = HashMap::new();
+        let mut connections = vec![];
 
-        for server in &config.servers {
-            let mut builder = SessionBuilder::default();
-            builder.known_hosts_check(KnownHosts::Accept);
-            if let Some(identity) = &config.identity {
-                builder.keyfile(identity);
-            }
-            builder.control_directory("/tmp");
-            let session = timeout(
-                Duration::from_secs(config.timeout.unwrap_or(5)),
-                builder.connect(format!("ssh://{}@{}", config.user, server.host)),
-            )
-            .await
-            .map_err(|_| anyhow!("Timeout connect to {}@{}", config.user, server.host))??;
+        let mut stream = futures::stream::iter(config.servers.iter().enumerate())
+            .map(|(idx, server)| {
+                let control_directory = control_directory.path();
+                async move {
+                    let begin_time = Instant::now();
+                    let session = connect(config, server, control_directory).await;
+                    (idx, server, session, begin_time.elapsed())
+                }
+            })
+            .buffer_unordered(concurrency);
 
-            sessions.insert(server.name(), session);
+        while let Some((idx, server, session, elapsed)) = stream.next().await {
+            let error = match session {
+                Ok(session) => {
+                    sessions.insert(server.name(), session);
+                    None
+                }
+                Err(error) if skip_unreachable => Some(error),
+                Err(error) => return Err(error),
+            };
+            connections.push((
+                idx,
+                ServerConnection {
+                    server_name: server.name(),
+                    elapsed,
+                    error,
+                },
+            ));
         }
+        drop(stream);
+        // Report the connections in the order of the config.
+        connections.sort_by_key(|(idx, _)| *idx);
+        let connections = connections
+            .into_iter()
+            .map(|(_, connection)| connection)
+            .collect();
 
         let client = RemoteConfigClient {
             config: config.clone(),
             sessions,
+            homes: Mutex::new(HashMap::new()),
+            connections,
+            control_directory: Some(control_directory),
         };
 
         Ok(client)
     }
 
+    pub fn connections(&self) -> &[ServerConnection] {
+        &self.connections
+    }
+
+    pub fn unreachable_servers(&self) -> impl Iterator<Item = &ServerConnection> {
+        self.connections
+            .iter()
+            .filter(|connection| connection.error.is_some())
+    }
+
+    fn user(&self, server_name: &str) -> Result<Option<&str>> {
+        Ok(self.config.server(server_name)?.user(&self.config))
+    }
+
+    /// Resolve `~` or `~user` to the home directory on the server, as the login user sees it.
+    async fn home(&self, server_name: &str, tilde: &str) -> Result<PathBuf> {
+        let key = (server_name.to_owned(), tilde.to_owned());
+        if let Some(home) = self.lock_homes()?.get(&key) {
+            return Ok(home.clone());
+        }
+        if !shell::is_tilde(tilde) {
+            return Err(anyhow!(
+                "Invalid home directory. (server={}, path={})",
+                server_name,
+                tilde
+            ));
+        }
+        // The shell expands the unquoted tilde, so the user's home is looked up on the server.
+        let mut command = OsString::from("printf %s ");
+        command.push(tilde);
+        let output = self
+            .run_command(server_name, command, RunAs::Login, None)
+            .await?;
+        if output.is_empty() || output.starts_with(b"~") {
+            return Err(anyhow!(
+                "Not found home directory. (server={}, path={})",
+                server_name,
+                tilde
+            ));
+        }
+        let home = bytes_to_path(&output);
+        self.lock_homes()?.insert(key, home.clone());
+        Ok(home)
+    }
+
+    fn lock_homes(&self) -> Result<MutexGuard<HashMap<(String, String), PathBuf>>> {
+        self.homes
+            .lock()
+            .map_err(|_| anyhow!("Home directory cache is poisoned."))
+    }
+
+    /// Path of the file on the server, with a leading `~` or `~user` expanded beforehand so that
+    /// it does not depend on the user `sudo` runs the command as. `~` is the home of the
+    /// `become` user when set.
+    async fn remote_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<PathBuf> {
+        let path = self.real_path(server_name, target, relative_path)?;
+        match split_tilde(&path, target.become_user.as_deref()) {
+            Some((tilde, rest)) => Ok(join_path(&self.home(server_name, &tilde).await?, &rest)),
+            None => Ok(path),
+        }
+    }
+
     async fn remote_session(&self, server_name: &str) -> Result<&Session> {
         let session = self
             .sessions
@@ -52,89 +583,242 @@ impl RemoteConfigClient {
         Ok(session)
     }
 
-    async fn remote_command(&self, server_name: &str, command: &str, sudo: bool) -> Result<String> {
-        let mut command = command.to_owned();
-        if sudo {
-            command = format!("sudo sh -c \"{}\"", command);
-        }
+    /// Run a command built from `args` as `run_as`, through the escalation command unless it is
+    /// the login user. Every argument is quoted, so paths are never interpreted by the remote shell.
+    async fn remote_command<S: AsRef<OsStr>>(
+        &self,
+        server_name: &str,
+        args: &[S],
+        run_as: RunAs<'_>,
+    ) -> Result<Vec<u8>> {
+        self.remote_command_with_stdin(server_name, args, run_as, None)
+            .await
+    }
 
-        let output = self
+    /// Same as `remote_command`, writing `stdin` to the standard input of the command.
+    async fn remote_command_with_stdin<S: AsRef<OsStr>>(
+        &self,
+        server_name: &str,
+        args: &[S],
+        run_as: RunAs<'_>,
+        stdin: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        self.run_command(server_name, shell::command_line(args), run_as, stdin)
+            .await
+    }
+
+    /// Run a command line as `run_as`, writing `stdin` to the standard input of the command.
+    async fn run_command(
+        &self,
+        server_name: &str,
+        command: OsString,
+        run_as: RunAs<'_>,
+        stdin: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let command = match run_as {
+            RunAs::Login => command,
+            RunAs::Root => escalate(self.config.escalation()?, &command, None),
+            RunAs::User(user) => escalate(self.config.escalation()?, &command, Some(user)),
+        };
+
+        let mut child = self
             .remote_session(server_name)
             .await?
             .raw_command(&command)
-            .output()
-            .await?;
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
 
-        let stdout = String::from_utf8(output.stdout)?;
-        let stderr = String::from_utf8(output.stderr)?;
+        let child_stdin = match stdin {
+            Some(_) => Some(
+                child
+                    .stdin()
+                    .take()
+                    .context("Failed to open stdin of command.")?,
+            ),
+            None => None,
+        };
+        // Stdin is written while the output is read, since the command may fill its stdout
+        // before it has read the whole stdin.
+        let write_stdin = async move {
+            if let (Some(mut child_stdin), Some(stdin)) = (child_stdin, stdin) {
+                child_stdin.write_all(stdin).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let (written, output) = tokio::join!(write_stdin, child.wait_with_output());
+        let output = output?;
 
+        // A command that failed may have closed its stdin early, so its stderr is reported
+        // instead of the failed write.
         if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if run_as != RunAs::Login && is_password_prompt(&stderr) {
+                return Err(anyhow!(
+                    "Privilege escalation requires a password. Allow it without a password or change remote.escalation. (server={}, stderr={})",
+                    server_name,
+                    stderr.trim()
+                ));
+            }
             return Err(anyhow!(
                 "Failed execute command\ncommand: {}\nstdout: {}\nstderr: {}",
-                command,
-                stdout,
-                stderr,
+                command.to_string_lossy(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
             ));
         }
+        written?;
 
-        Ok(stdout)
+        Ok(output.stdout)
     }
 
-    pub async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool> {
-        let command = format!("ls {}", target.path);
-        let exists = self
-            .remote_command(server_name, &command, target.sudo)
-            .await
-            .is_ok();
-        Ok(exists)
+    pub async fn close(&mut self) -> Result<()> {
+        let servers = self.sessions.keys().cloned().collect_vec();
+        for server in servers {
+            let session = self
+                .sessions
+                .remove(&server)
+                .with_context(|| format!("Not found session. (server={})", &server))?;
+            session.close().await?;
+        }
+        if let Some(control_directory) = self.control_directory.take() {
+            control_directory.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ConfigStore for RemoteConfigClient {
+    fn is_available(&self, server_name: &str) -> bool {
+        self.sessions.contains_key(server_name)
     }
 
-    pub async fn exists_relative_path(
+    async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool> {
+        let path = self.remote_path(server_name, target, Path::new("")).await?;
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(EXISTS_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+        ];
+        let output = self
+            .remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        Ok(output == b"1")
+    }
+
+    async fn exists_relative_path(
         &self,
         server_name: &str,
         target: &TargetConfig,
         relative_path: &Path,
     ) -> Result<bool> {
-        let path = self.real_path(server_name, target, relative_path)?;
-        let command = format!("ls {}", convert_to_string(&path)?);
-        let exists = self
-            .remote_command(server_name, &command, target.sudo)
-            .await
-            .is_ok();
-        Ok(exists)
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(EXISTS_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+        ];
+        let output = self
+            .remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        Ok(output == b"1")
     }
 
-    pub async fn file_relative_paths(
+    async fn file_relative_paths(
         &self,
         server_name: &str,
         target: &TargetConfig,
     ) -> Result<Vec<PathBuf>> {
-        if !self.exists(server_name, target).await? {
-            return Ok(vec![]);
-        }
-        let command = format!("find {} -type f -o -type l", target.path);
-        let result = self
-            .remote_command(server_name, &command, target.sudo)
+        let path = self.remote_path(server_name, target, Path::new("")).await?;
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(LIST_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+        ];
+        let output = self
+            .remote_command(server_name, &command, RunAs::of(target))
             .await?;
-        let paths: Result<Vec<_>, _> = result
-            .split_whitespace()
-            .filter_map(|s| {
-                if s.is_empty() {
-                    return None;
-                }
-                let home_prefix = format!("/home/{}", self.config.user);
-                if target.path.starts_with('~') && s.starts_with(&home_prefix) {
-                    return Some(s.replacen(&home_prefix, "~", 1));
-                }
-                Some(s.to_owned())
-            })
-            .map(|s| Path::new(&s).to_owned())
-            .map(|p| p.strip_prefix(&target.path).map(|path| path.to_owned()))
-            .collect();
-        Ok(paths?)
+        let filter = target.path_filter()?;
+        Ok(parse_list(&output)?
+            .into_iter()
+            .filter(|path| filter.is_match(path))
+            .collect())
     }
 
-    pub fn real_path(
+    async fn expand(&self, server_name: &str, target: &TargetConfig) -> Result<Vec<GlobMatch>> {
+        let base = TargetConfig {
+            path: target.glob_base().to_owned(),
+            ..target.to_owned()
+        };
+        let root = self.remote_path(server_name, &base, Path::new("")).await?;
+        let pattern = join_path(&root, Path::new(target.glob_pattern()));
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(EXPAND_SCRIPT),
+            OsStr::new("sh"),
+            pattern.as_os_str(),
+        ];
+        let output = self
+            .remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        parse_glob_matches(&root, &output)
+    }
+
+    async fn inventory(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        max_file_size: u64,
+    ) -> Result<Vec<FileEntry>> {
+        let follow = if target.follow_symlinks { "1" } else { "" };
+        let max_file_size = max_file_size.to_string();
+        let path = self.remote_path(server_name, target, Path::new("")).await?;
+        let filter = target.path_filter()?;
+        // With include or exclude, the files are listed and filtered first, so that excluded
+        // files are never read. This costs a second round trip for filtered targets.
+        let stdin = if filter.is_empty() {
+            None
+        } else {
+            let mut stdin = vec![];
+            for relative_path in self.file_relative_paths(server_name, target).await? {
+                stdin.extend_from_slice(join_path(&path, &relative_path).as_os_str().as_bytes());
+                stdin.push(b'\0');
+            }
+            Some(stdin)
+        };
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(INVENTORY_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+            OsStr::new(follow),
+            OsStr::new(&max_file_size),
+            OsStr::new(if stdin.is_some() { "1" } else { "" }),
+        ];
+        let output = self
+            .remote_command_with_stdin(server_name, &command, RunAs::of(target), stdin.as_deref())
+            .await?;
+        Ok(parse_inventory(&output)?
+            .into_iter()
+            .filter(|entry| filter.is_match(&entry.relative_path))
+            .collect())
+    }
+
+    fn real_path(
         &self,
         _server_name: &str,
         target: &TargetConfig,
@@ -143,135 +827,574 @@ impl RemoteConfigClient {
         Ok(join_path(Path::new(&target.path), relative_path))
     }
 
-    pub async fn len(
+    fn display_path(
         &self,
         server_name: &str,
         target: &TargetConfig,
         relative_path: &Path,
-    ) -> Result<u64> {
+    ) -> Result<String> {
         let path = self.real_path(server_name, target, relative_path)?;
-        let mut path = convert_to_string(&path)?;
-
-        if !target.sudo && path.starts_with('~') {
-            path = path.replacen('~', format!("/home/{}", self.config.user).as_str(), 1);
+        match self.user(server_name)? {
+            Some(user) => Ok(format!("{}@{}:{}", user, server_name, path.display())),
+            None => Ok(format!("{}:{}", server_name, path.display())),
         }
+    }
 
-        self.remote_command(server_name, &format!("stat -L -c %s {}", path), target.sudo)
-            .await?
-            .trim()
-            .parse::<u64>()
-            .map_err(|_| anyhow!("Failed to parse stat result."))
+    async fn len(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<u64> {
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let command = [
+            OsStr::new("stat"),
+            OsStr::new("-L"),
+            OsStr::new("-c"),
+            OsStr::new("%s"),
+            OsStr::new("--"),
+            path.as_os_str(),
+        ];
+        String::from_utf8_lossy(
+            &self
+                .remote_command(server_name, &command, RunAs::of(target))
+                .await?,
+        )
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Failed to parse stat result."))
     }
 
-    pub async fn get(
+    async fn get(
         &self,
         server_name: &str,
         target: &TargetConfig,
         relative_path: &Path,
     ) -> Result<Vec<u8>> {
-        let path = self.real_path(server_name, target, relative_path)?;
-        let session = self.remote_session(server_name).await?;
-        let mut config = vec![];
-
-        if target.sudo {
-            let tmp_path = format!("/tmp/{}", Local::now().to_rfc3339());
-
-            self.remote_command(
-                server_name,
-                &format!("cp {} {}", convert_to_string(&path)?, tmp_path),
-                true,
-            )
-            .await?;
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        // The content is streamed through the command, so it is never written to a temp file.
+        let command = [OsStr::new("cat"), OsStr::new("--"), path.as_os_str()];
+        self.remote_command(server_name, &command, RunAs::of(target))
+            .await
+    }
 
-            self.remote_command(server_name, &format!("chmod 644 {}", tmp_path), true)
-                .await?;
+    async fn create(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        config_bytes: Vec<u8>,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let (chown, mode) = metadata_args(metadata);
+        let follow = if target.follow_symlinks { "1" } else { "" };
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(WRITE_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+            OsStr::new(&chown),
+            OsStr::new(&mode),
+            OsStr::new(follow),
+        ];
+        self.remote_command_with_stdin(
+            server_name,
+            &command,
+            RunAs::of(target),
+            Some(&config_bytes),
+        )
+        .await?;
 
-            let mut remote_file = session.sftp().read_from(&tmp_path).await?;
-            remote_file.read_to_end(&mut config).await?;
-            remote_file.close().await?;
+        Ok(())
+    }
 
-            self.remote_command(server_name, &format!("rm {}", tmp_path), true)
-                .await?;
-        } else {
-            let mut path = convert_to_string(&path)?;
-            if path.starts_with('~') {
-                path = path.replacen('~', format!("/home/{}", self.config.user).as_str(), 1);
-            }
-            let mut remote_file = session.sftp().read_from(&path).await?;
-            remote_file.read_to_end(&mut config).await?;
-            remote_file.close().await?;
-        }
+    async fn create_link(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        link_target: &Path,
+    ) -> Result<()> {
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(LINK_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+            link_target.as_os_str(),
+        ];
+        self.remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        Ok(())
+    }
 
-        Ok(config)
+    async fn remove(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<()> {
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let command = [
+            OsStr::new("rm"),
+            OsStr::new("-f"),
+            OsStr::new("--"),
+            path.as_os_str(),
+        ];
+        self.remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        Ok(())
     }
 
-    pub async fn create(
+    async fn set_metadata(
         &self,
         server_name: &str,
         target: &TargetConfig,
         relative_path: &Path,
-        config_bytes: Vec<u8>,
+        metadata: &FileMetadata,
     ) -> Result<()> {
-        let path = self.real_path(server_name, target, relative_path)?;
-        let session = self.remote_session(server_name).await?;
+        let path = self.remote_path(server_name, target, relative_path).await?;
+        let (chown, mode) = metadata_args(metadata);
+        let command = [
+            OsStr::new("sh"),
+            OsStr::new("-c"),
+            OsStr::new(METADATA_SCRIPT),
+            OsStr::new("sh"),
+            path.as_os_str(),
+            OsStr::new(&chown),
+            OsStr::new(&mode),
+        ];
+        self.remote_command(server_name, &command, RunAs::of(target))
+            .await?;
+        Ok(())
+    }
+}
 
-        if target.sudo {
-            let tmp_path = format!("/tmp/{}", Local::now().to_rfc3339());
-            let mut remote_file = session.sftp().write_to(&tmp_path).await?;
-            remote_file.write_all(&config_bytes).await?;
-            remote_file.close().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
 
-            if let Some(parent) = path.parent() {
-                self.remote_command(
-                    server_name,
-                    &format!("mkdir -p {}", convert_to_string(parent)?),
-                    true,
-                )
-                .await?;
-            }
+    fn remote_config(yaml: &str) -> RemoteConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
 
-            self.remote_command(
-                server_name,
-                &format!("cp {} {}", tmp_path, convert_to_string(&path)?),
-                true,
+    #[test]
+    fn ssh_config_scopes_proxy_jump_to_destination() {
+        let config = remote_config(
+            r#"
+servers:
+  - host: target.example
+proxy_jump: bastion.example
+known_hosts_file: /tmp/known hosts
+"#,
+        );
+        assert_eq!(
+            ssh_config(&config, &config.servers[0]).unwrap().unwrap(),
+            concat!(
+                "Host target.example\n",
+                "  ProxyJump bastion.example\n",
+                "Host *\n",
+                "  UserKnownHostsFile \"/tmp/known hosts\"\n",
+                "Include ~/.ssh/config\n",
+                "Include /etc/ssh/ssh_config\n",
             )
-            .await?;
+        );
+    }
 
-            self.remote_command(server_name, &format!("rm {}", tmp_path), true)
-                .await?;
-        } else {
-            let mut path = convert_to_string(&path)?;
-            if path.starts_with('~') {
-                path = path.replacen('~', format!("/home/{}", self.config.user).as_str(), 1);
-            }
-            let path = Path::new(&path);
+    #[test]
+    fn ssh_config_without_proxy_jump() {
+        let config = remote_config(
+            r#"
+servers:
+  - host: target.example
+known_hosts_file: /tmp/known_hosts
+"#,
+        );
+        let ssh_config = ssh_config(&config, &config.servers[0]).unwrap().unwrap();
+        assert!(ssh_config.starts_with("Host *\n"));
+        assert!(!ssh_config.contains("ProxyJump"));
+    }
 
-            if let Some(parent) = path.parent() {
-                self.remote_command(
-                    server_name,
-                    &format!("mkdir -p {}", convert_to_string(parent)?),
-                    false,
-                )
-                .await?;
-            }
+    #[test]
+    fn ssh_config_is_not_needed_without_options() {
+        let config = remote_config("servers:\n  - host: target.example\n");
+        assert!(ssh_config(&config, &config.servers[0]).unwrap().is_none());
+    }
+
+    fn run_sh(command: &OsStr) -> Vec<u8> {
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .output()
+            .unwrap()
+            .stdout
+    }
+
+    #[test]
+    fn escalate_quotes_the_command() {
+        let command = shell::command_line(&["cat", "--", "/a b"]);
+        assert_eq!(
+            escalate(Escalation::Sudo, &command, None),
+            r"sudo sh -c 'cat -- '\''/a b'\'''"
+        );
+        assert_eq!(
+            escalate(Escalation::SudoNonInteractive, &command, Some("isucon")),
+            r"sudo -n -u isucon sh -c 'cat -- '\''/a b'\'''"
+        );
+        assert_eq!(
+            escalate(
+                Escalation::Template("su {user} -c {command}".to_owned()),
+                &command,
+                Some("web app")
+            ),
+            r"su 'web app' -c 'cat -- '\''/a b'\'''"
+        );
+    }
+
+    #[test]
+    fn nested_escalation_passes_arguments_literally() {
+        let word = OsStr::from_bytes(b"it's $HOME `id` ~\n\xff");
+        let command = shell::command_line(&[OsStr::new("printf"), OsStr::new("%s"), word]);
+        let template = Escalation::Template("sh -c {command}".to_owned());
+        let escalated = escalate(template.clone(), &command, None);
+        let escalated = escalate(template, &escalated, None);
+        assert_eq!(run_sh(&escalated), word.as_bytes());
+    }
+
+    #[test]
+    fn link_target_is_not_tilde_expanded() {
+        let command = shell::command_line(&["printf", "%s", "~/x"]);
+        assert_eq!(run_sh(&command), b"~/x");
+    }
 
-            let mut remote_file = session.sftp().write_to(path).await?;
-            remote_file.write_all(&config_bytes).await?;
-            remote_file.close().await?;
+    #[test]
+    fn parse_inventory_keeps_unreadable_and_unhashed_files() {
+        let output = b"/etc\0/etc/secret\0e\0/etc/big\0f 100 644 root root 1 -\0\
+            /etc/s\0f 6 600 root adm 1 abc\0/etc/link\0l 1 777 root root 1\0s\0";
+        let entries = parse_inventory(output).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].relative_path, Path::new("secret"));
+        assert!(!entries[0].is_readable);
+        assert_eq!((entries[1].size, entries[1].hash.as_deref()), (100, None));
+        assert!(entries[1].is_readable);
+        assert_eq!(entries[2].hash.as_deref(), Some("abc"));
+        assert_eq!(entries[2].metadata.mode, Some(0o600));
+        assert_eq!(entries[3].link_target.as_deref(), Some(Path::new("s")));
+    }
+
+    #[test]
+    fn parse_list_keeps_names_with_spaces_newlines_and_non_utf8_bytes() {
+        let output = b"/etc\0/etc/a b\0/etc/x\ny\0/etc/\xff\0";
+        assert_eq!(
+            parse_list(output).unwrap(),
+            vec![
+                bytes_to_path(b"a b"),
+                bytes_to_path(b"x\ny"),
+                bytes_to_path(b"\xff"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_inventory_keeps_names_with_spaces_newlines_and_non_utf8_bytes() {
+        let output = b"/etc\0/etc/a b\0f 1 644 root root 1 abc\0\
+            /etc/x\ny\0l 1 777 root root 1\0/etc/a b\0/etc/\xff\0e\0";
+        let entries = parse_inventory(output).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.relative_path.clone())
+                .collect_vec(),
+            vec![
+                bytes_to_path(b"a b"),
+                bytes_to_path(b"x\ny"),
+                bytes_to_path(b"\xff"),
+            ]
+        );
+        assert_eq!(entries[0].hash.as_deref(), Some("abc"));
+        assert_eq!(entries[1].link_target, Some(bytes_to_path(b"/etc/a b")));
+        assert!(!entries[2].is_readable);
+    }
+
+    #[test]
+    fn list_script_output_is_parsed_back_to_the_same_names() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [&b"a b"[..], b"x\ny", b"\xff"] {
+            fs::write(dir.path().join(OsStr::from_bytes(name)), b"").unwrap();
         }
+        let output = run_script(LIST_SCRIPT, &[dir.path().as_os_str()], b"");
+        let mut paths = parse_list(&output.stdout).unwrap();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                bytes_to_path(b"a b"),
+                bytes_to_path(b"x\ny"),
+                bytes_to_path(b"\xff"),
+            ]
+        );
+    }
 
-        Ok(())
+    fn run_script(script: &str, args: &[&OsStr], stdin: &[u8]) -> std::process::Output {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .arg("sh")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
     }
 
-    pub async fn close(&mut self) -> Result<()> {
-        let servers = self.sessions.keys().cloned().collect_vec();
-        for server in servers {
-            let session = self
-                .sessions
-                .remove(&server)
-                .with_context(|| format!("Not found session. (server={})", &server))?;
-            session.close().await?;
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// Names in `dir` left by the scripts, such as temporary files.
+    fn file_names(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .sorted()
+            .collect()
+    }
+
+    fn write(path: &Path, content: &[u8], follow: &str) -> std::process::Output {
+        let args = [
+            path.as_os_str(),
+            OsStr::new(""),
+            OsStr::new(""),
+            OsStr::new(follow),
+        ];
+        run_script(WRITE_SCRIPT, &args, content)
+    }
+
+    #[test]
+    fn write_script_keeps_the_mode_on_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("my.cnf");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        assert!(write(&path, b"new", "").status.success());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(file_names(dir.path()), vec!["my.cnf"]);
+    }
+
+    #[test]
+    fn write_script_applies_the_umask_to_a_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conf.d/my.cnf");
+        let script = format!("umask 027\n{}", WRITE_SCRIPT);
+        let args = [
+            path.as_os_str(),
+            OsStr::new(""),
+            OsStr::new(""),
+            OsStr::new(""),
+        ];
+
+        assert!(run_script(&script, &args, b"new").status.success());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o640);
+    }
+
+    #[test]
+    fn write_script_replaces_a_symlink_unless_following_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        let link = dir.path().join("link");
+        fs::write(&real, b"old").unwrap();
+        std::os::unix::fs::symlink("real", &link).unwrap();
+
+        assert!(write(&link, b"through", "1").status.success());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("real"));
+        assert_eq!(fs::read(&real).unwrap(), b"through");
+
+        assert!(write(&link, b"replaced", "").status.success());
+        assert!(!fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read(&link).unwrap(), b"replaced");
+        assert_eq!(fs::read(&real).unwrap(), b"through");
+        assert_eq!(file_names(dir.path()), vec!["link", "real"]);
+    }
+
+    #[test]
+    fn write_script_leaves_no_temp_file_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("my.cnf");
+        fs::write(&path, b"old").unwrap();
+        let args = [
+            path.as_os_str(),
+            OsStr::new("isuconf-no-such-user:isuconf-no-such-group"),
+            OsStr::new(""),
+            OsStr::new(""),
+        ];
+
+        assert!(!run_script(WRITE_SCRIPT, &args, b"new").status.success());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(file_names(dir.path()), vec!["my.cnf"]);
+    }
+
+    #[test]
+    fn link_script_replaces_a_file_with_a_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("default");
+        fs::write(&path, b"old").unwrap();
+        let args = [path.as_os_str(), OsStr::new("../sites-available/default")];
+
+        assert!(run_script(LINK_SCRIPT, &args, b"").status.success());
+        assert_eq!(
+            fs::read_link(&path).unwrap(),
+            Path::new("../sites-available/default")
+        );
+        assert_eq!(file_names(dir.path()), vec!["default"]);
+    }
+
+    #[test]
+    fn inventory_script_records_files_and_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("small"), b"abc").unwrap();
+        fs::write(dir.path().join("large"), b"0123456789").unwrap();
+        std::os::unix::fs::symlink("small", dir.path().join("link")).unwrap();
+        fs::set_permissions(dir.path().join("small"), fs::Permissions::from_mode(0o640)).unwrap();
+        let inventory = |follow: &str, stdin: Option<&[u8]>| {
+            let args = [
+                dir.path().as_os_str(),
+                OsStr::new(follow),
+                OsStr::new("4"),
+                OsStr::new(if stdin.is_some() { "1" } else { "" }),
+            ];
+            let output = run_script(INVENTORY_SCRIPT, &args, stdin.unwrap_or_default());
+            assert!(output.status.success());
+            let mut entries = parse_inventory(&output.stdout).unwrap();
+            entries.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+            entries
+        };
+
+        let entries = inventory("", None);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.relative_path.to_str().unwrap())
+                .collect_vec(),
+            vec!["large", "link", "small"]
+        );
+        assert_eq!((entries[0].size, entries[0].hash.as_deref()), (10, None));
+        assert_eq!(entries[1].link_target.as_deref(), Some(Path::new("small")));
+        assert_eq!(
+            entries[2].hash.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(entries[2].metadata.mode, Some(0o640));
+
+        let entries = inventory("1", None);
+        assert_eq!(entries[1].link_target, None);
+        assert_eq!(entries[1].hash, entries[2].hash);
+
+        let stdin = join_path(dir.path(), Path::new("small"));
+        let mut stdin = stdin.as_os_str().as_bytes().to_vec();
+        stdin.push(b'\0');
+        let entries = inventory("", Some(&stdin));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].relative_path, Path::new("small"));
+    }
+
+    #[test]
+    fn split_tilde_uses_the_home_of_the_become_user() {
+        for (path, become_user, expected) in [
+            ("/etc/hosts", None, None),
+            ("/etc/hosts", Some("isucon"), None),
+            ("~", None, Some(("~", ""))),
+            ("~", Some("isucon"), Some(("~isucon", ""))),
+            ("~/x", None, Some(("~", "x"))),
+            ("~/x", Some("isucon"), Some(("~isucon", "x"))),
+            ("~other/x", None, Some(("~other", "x"))),
+            ("~other/x", Some("isucon"), Some(("~other", "x"))),
+        ] {
+            assert_eq!(
+                split_tilde(Path::new(path), become_user),
+                expected.map(|(tilde, rest)| (tilde.to_owned(), PathBuf::from(rest))),
+                "{} {:?}",
+                path,
+                become_user
+            );
+        }
+    }
+
+    #[test]
+    fn run_as_prefers_become_over_sudo() {
+        let target = |yaml: &str| serde_yaml::from_str::<TargetConfig>(yaml).unwrap();
+        let login = target("path: /etc/hosts");
+        let root = target("path: /etc/hosts\nsudo: true");
+        let user = target("path: /etc/hosts\nbecome: isucon");
+        let both = target("path: /etc/hosts\nsudo: true\nbecome: isucon");
+        assert_eq!(RunAs::of(&login), RunAs::Login);
+        assert_eq!(RunAs::of(&root), RunAs::Root);
+        assert_eq!(RunAs::of(&user), RunAs::User("isucon"));
+        assert_eq!(RunAs::of(&both), RunAs::User("isucon"));
+    }
+
+    #[test]
+    fn expand_script_matches_the_same_paths_as_local_expansion() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("conf.d")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        let paths = [
+            ".hidden.conf",
+            "a.conf",
+            "conf.d/.b.conf",
+            "conf.d/c.conf",
+            ".git/d.conf",
+        ]
+        .map(PathBuf::from);
+        for path in &paths {
+            fs::write(dir.path().join(path), b"").unwrap();
+        }
+        for pattern in ["*.conf", ".*.conf", "*/*.conf", "*", ".*/*.conf"] {
+            let target = TargetConfig {
+                path: format!("{}/{}", dir.path().to_str().unwrap(), pattern),
+                ..serde_yaml::from_str("path: /").unwrap()
+            };
+            let glob = dir.path().join(pattern);
+            let output = run_script(EXPAND_SCRIPT, &[glob.as_os_str()], b"");
+            assert_eq!(
+                parse_glob_matches(dir.path(), &output.stdout).unwrap(),
+                crate::client::match_glob(&target, &paths).unwrap(),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn password_prompts_are_matched_by_their_format() {
+        for stderr in [
+            "[sudo] password for isucon: ",
+            "sudo: a password is required\n",
+            "sudo: a terminal is required to read the password; either use the -S option to read from standard input or configure an askpass helper\n",
+            "sudo: no tty present and no askpass program specified\n",
+            "doas (isucon@is1) password: ",
+            "doas: a password is required\n",
+            "su: must be run from a terminal\n",
+            "Password: \n",
+        ] {
+            assert!(is_password_prompt(stderr), "{}", stderr);
+        }
+        for stderr in [
+            "cat: /etc/shadow: Permission denied\n",
+            "mysql: [Warning] Using a password on the command line interface can be insecure.\n",
+            "Password: changed\n",
+            "error: password is required for the database\n",
+        ] {
+            assert!(!is_password_prompt(stderr), "{}", stderr);
         }
-        Ok(())
     }
 }
@@ -0,0 +1,126 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Bytes that never need quoting in a POSIX shell word.
+fn is_safe(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"@%+=:,./_-".contains(&b)
+}
+
+/// Whether the word is `~` or `~user`, which the shell expands to a home directory when it is
+/// left unquoted.
+pub fn is_tilde(word: &str) -> bool {
+    match word.strip_prefix('~') {
+        Some(user) => user
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b)),
+        None => false,
+    }
+}
+
+/// Quote a word for a POSIX shell, so that the shell passes it through literally. Bytes that are
+/// not UTF-8 are passed through as they are.
+pub fn quote<S: AsRef<OsStr>>(word: S) -> OsString {
+    let word = word.as_ref().as_bytes();
+    if !word.is_empty() && word.iter().copied().all(is_safe) {
+        return OsStr::from_bytes(word).to_owned();
+    }
+    let mut quoted = vec![b'\''];
+    for &b in word {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
+
+/// Build a command line from its arguments, quoting each of them.
+pub fn command_line<S: AsRef<OsStr>>(args: &[S]) -> OsString {
+    let mut command = OsString::new();
+    for (idx, arg) in args.iter().enumerate() {
+        if idx >= 1 {
+            command.push(" ");
+        }
+        command.push(quote(arg));
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// Words the shell reads back from the command line, one per line.
+    fn shell_words(command: &OsStr) -> Vec<u8> {
+        let mut script = OsString::from("printf '%s\\n' ");
+        script.push(command);
+        Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap()
+            .stdout
+    }
+
+    fn assert_round_trip(word: &[u8]) {
+        let quoted = quote(OsStr::from_bytes(word));
+        let mut expected = word.to_vec();
+        expected.push(b'\n');
+        assert_eq!(shell_words(&quoted), expected, "quoted={:?}", quoted);
+    }
+
+    #[test]
+    fn quote_keeps_safe_words() {
+        assert_eq!(quote("/etc/nginx/nginx.conf"), "/etc/nginx/nginx.conf");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn quote_passes_special_characters_literally() {
+        for word in [
+            &b"with space"[..],
+            b"it's",
+            b"\"double\"",
+            b"$HOME ${x} $(id)",
+            b"`id`",
+            b"line\nbreak",
+            b"*?[a]",
+            b"a;b|c&d>e<f",
+            b"\\back\\slash",
+            b"",
+            b"\xff\xfe not utf-8",
+        ] {
+            assert_round_trip(word);
+        }
+    }
+
+    #[test]
+    fn quote_does_not_expand_tilde() {
+        assert_eq!(quote("~"), "'~'");
+        assert_eq!(quote("~/x"), "'~/x'");
+        assert_round_trip(b"~");
+        assert_round_trip(b"~root/x");
+        assert_round_trip(b"../~/x");
+    }
+
+    #[test]
+    fn command_line_quotes_each_argument() {
+        let command = command_line(&["a b", "$c", "~"]);
+        assert_eq!(command, "'a b' '$c' '~'");
+        assert_eq!(shell_words(&command), b"a b\n$c\n~\n");
+    }
+
+    #[test]
+    fn is_tilde_accepts_user_names_only() {
+        assert!(is_tilde("~"));
+        assert!(is_tilde("~isucon"));
+        assert!(is_tilde("~web-app.1"));
+        assert!(!is_tilde("isucon"));
+        assert!(!is_tilde("~/x"));
+        assert!(!is_tilde("~$(id)"));
+        assert!(!is_tilde("~a b"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::TargetConfig;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+/// Ownership and permissions of a file. Fields that are `None` are not managed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl FileMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none() && self.group.is_none()
+    }
+
+    /// Whether every field set in `self` has the same value in `other`.
+    pub fn is_satisfied_by(&self, other: &FileMetadata) -> bool {
+        (self.mode.is_none() || self.mode == other.mode)
+            && (self.owner.is_none() || self.owner == other.owner)
+            && (self.group.is_none() || self.group == other.group)
+    }
+
+    /// Overwrite the fields of `self` with the fields set in `other`.
+    pub fn merge(&mut self, other: &FileMetadata) {
+        if other.mode.is_some() {
+            self.mode = other.mode;
+        }
+        if other.owner.is_some() {
+            self.owner = other.owner.clone();
+        }
+        if other.group.is_some() {
+            self.group = other.group.clone();
+        }
+    }
+}
+
+/// A file of a target with its metadata. Metadata a store cannot observe is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub relative_path: PathBuf,
+    pub size: u64,
+    pub metadata: FileMetadata,
+    pub mtime: Option<i64>,
+    /// Hex encoded sha256 of the content.
+    pub hash: Option<String>,
+    /// Target of the symlink, when the file is a symlink that is not followed.
+    pub link_target: Option<PathBuf>,
+    /// Whether the file could be read. An unreadable file is listed instead of being left out,
+    /// so that it is not mistaken for a missing file.
+    pub is_readable: bool,
+}
+
+impl FileEntry {
+    /// Whether the content exceeds `max_file_size`. A symlink is created from its target, so
+    /// it is never too large.
+    pub fn is_too_large(&self, max_file_size: u64) -> bool {
+        self.link_target.is_none() && self.size > max_file_size
+    }
+}
+
+/// A path matched by the glob path of a target, relative to its `glob_base`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobMatch {
+    pub relative_path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// Storage of config files, addressed by server, target and the path relative to the target.
+#[async_trait]
+pub trait ConfigStore: Send + Sync {
+    /// Whether the server can be operated. Unavailable servers are left out of the sync.
+    fn is_available(&self, _server_name: &str) -> bool {
+        true
+    }
+
+    async fn exists(&self, server_name: &str, target: &TargetConfig) -> Result<bool>;
+
+    async fn exists_relative_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<bool>;
+
+    async fn file_relative_paths(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+    ) -> Result<Vec<PathBuf>>;
+
+    /// Expand the glob path of the target into the paths that exist on the server.
+    async fn expand(&self, server_name: &str, target: &TargetConfig) -> Result<Vec<GlobMatch>>;
+
+    /// List the files of the target with their metadata. Files larger than `max_file_size` are
+    /// not hashed.
+    async fn inventory(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        max_file_size: u64,
+    ) -> Result<Vec<FileEntry>>;
+
+    fn real_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<PathBuf>;
+
+    /// Human readable location of the file, used in command output.
+    fn display_path(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<String>;
+
+    async fn len(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<u64>;
+
+    async fn get(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<Vec<u8>>;
+
+    async fn create(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        config_bytes: Vec<u8>,
+        metadata: &FileMetadata,
+    ) -> Result<()>;
+
+    /// Replace the file with a symlink to `link_target`.
+    async fn create_link(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        link_target: &Path,
+    ) -> Result<()>;
+
+    /// Delete the file.
+    async fn remove(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+    ) -> Result<()>;
+
+    /// Apply the fields set in `metadata` to an existing file.
+    async fn set_metadata(
+        &self,
+        server_name: &str,
+        target: &TargetConfig,
+        relative_path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<()>;
+
+    /// Persist the changes kept in memory, after the actions of a plan are applied.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -2,3 +2,4 @@ pub mod pull;
 pub mod push;
 pub mod ssh;
 pub mod ssh_config;
+pub mod sync;
@@ -1,5 +1,4 @@
-
-use crate::config::{read_config};
+use crate::config::read_config;
 use anyhow::Result;
 use structopt::StructOpt;
 
@@ -25,12 +24,26 @@ pub async fn ssh(opt: SshOpt) -> Result<()> {
         .find(|server| server.name() == server_name);
 
     if let Some(server) = server {
-        print!("ssh {}@{}", remote.user, server.host);
+        print!("ssh ");
+
+        if let Some(user) = server.user(&remote) {
+            print!("{}@", user)
+        }
+
+        print!("{}", server.destination(&remote)?);
 
-        if let Some(identity) = &remote.identity {
+        if let Some(port) = server.port(&remote) {
+            print!(" -p {}", port)
+        }
+
+        if let Some(identity) = server.identity(&remote) {
             print!(" -i {}", identity)
         }
 
+        if let Some(proxy_jump) = server.proxy_jump(&remote) {
+            print!(" -J {}", proxy_jump)
+        }
+
         println!();
     }
 
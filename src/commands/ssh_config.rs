@@ -1,5 +1,4 @@
-
-use crate::config::{read_config};
+use crate::config::read_config;
 use anyhow::Result;
 use structopt::StructOpt;
 
@@ -15,11 +14,28 @@ pub async fn ssh_config(opt: SshConfigOpt) -> Result<()> {
     let remote = &cli_config.remote;
 
     for server in &remote.servers {
+        if server.uses_ssh_config(remote) {
+            println!(
+                "# {} is resolved from Host {}",
+                server.name(),
+                server.destination(remote)?
+            );
+            println!();
+            continue;
+        }
         println!("Host {}", server.name());
-        println!("  HostName {}", &server.host);
-        println!("  User {}", &remote.user);
-        if let Some(identity) = &remote.identity {
-            print!("  IdentityFile {}", identity)
+        println!("  HostName {}", server.destination(remote)?);
+        if let Some(user) = server.user(remote) {
+            println!("  User {}", user)
+        }
+        if let Some(port) = server.port(remote) {
+            println!("  Port {}", port)
+        }
+        if let Some(identity) = server.identity(remote) {
+            println!("  IdentityFile {}", identity)
+        }
+        if let Some(proxy_jump) = server.proxy_jump(remote) {
+            println!("  ProxyJump {}", proxy_jump)
         }
         println!();
     }
@@ -0,0 +1,267 @@
+use crate::client::{ConfigStore, RemoteConfigClient};
+use crate::config::CliConfig;
+use crate::sync::{SyncAction, SyncActionKind, SyncDirection, SyncEngine};
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use itertools::Itertools;
+use std::cmp::max;
+
+#[derive(Debug)]
+pub enum SourceState {
+    Skip,
+    NotApplicable,
+    TooLarge,
+    Unreadable,
+    NotExists,
+    Synced,
+    FoundDiff,
+    FoundMetadataDiff,
+    FoundNewFile,
+    FoundRemovedFile,
+}
+
+impl SourceState {
+    fn message(&self, file_message: &str) -> String {
+        let icon = match self {
+            SourceState::NotExists => "✕".red(),
+            SourceState::TooLarge => " ".normal(),
+            SourceState::Unreadable => "✕".red(),
+            SourceState::Skip => " ".normal(),
+            SourceState::NotApplicable => " ".normal(),
+            SourceState::Synced => " ".normal(),
+            SourceState::FoundDiff => " ".normal(),
+            SourceState::FoundMetadataDiff => " ".normal(),
+            SourceState::FoundNewFile => " ".normal(),
+            SourceState::FoundRemovedFile => " ".normal(),
+        };
+        let message = match self {
+            SourceState::NotExists => "not exists".normal(),
+            SourceState::TooLarge => "too large".normal(),
+            SourceState::Unreadable => "unreadable".normal(),
+            SourceState::Skip => "skip".normal(),
+            SourceState::NotApplicable => "not applicable".normal(),
+            SourceState::Synced => "synced 📌".normal(),
+            SourceState::FoundDiff => "found diff 🔍".normal(),
+            SourceState::FoundMetadataDiff => "found metadata diff 🔍".normal(),
+            SourceState::FoundNewFile => "found new file 🔍".normal(),
+            SourceState::FoundRemovedFile => "found removed file 🔍".normal(),
+        };
+        let file_message = match self {
+            SourceState::NotExists => file_message.red(),
+            SourceState::TooLarge => file_message.normal(),
+            SourceState::Unreadable => file_message.red(),
+            SourceState::Skip => file_message.normal(),
+            SourceState::NotApplicable => file_message.normal(),
+            SourceState::Synced => file_message.normal(),
+            SourceState::FoundDiff => file_message.normal(),
+            SourceState::FoundMetadataDiff => file_message.normal(),
+            SourceState::FoundNewFile => file_message.normal(),
+            SourceState::FoundRemovedFile => file_message.normal(),
+        };
+        format!("▕  {} ▕  {}  ▕  {} ", file_message, icon, message)
+    }
+}
+
+impl From<&SyncActionKind> for SourceState {
+    fn from(kind: &SyncActionKind) -> Self {
+        match kind {
+            SyncActionKind::Skip => SourceState::Skip,
+            SyncActionKind::NotApplicable => SourceState::NotApplicable,
+            SyncActionKind::TooLarge => SourceState::TooLarge,
+            SyncActionKind::Unreadable => SourceState::Unreadable,
+            SyncActionKind::NotExists => SourceState::NotExists,
+            SyncActionKind::Synced => SourceState::Synced,
+            SyncActionKind::Create => SourceState::FoundNewFile,
+            SyncActionKind::Update => SourceState::FoundDiff,
+            SyncActionKind::UpdateMetadata => SourceState::FoundMetadataDiff,
+            SyncActionKind::Delete => SourceState::FoundRemovedFile,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DestinationState {
+    Create,
+    Update,
+    UpdateMetadata,
+    Delete,
+}
+
+impl DestinationState {
+    fn message(&self, file_message: &str) -> String {
+        let icon = match self {
+            DestinationState::Create => "✓".green(),
+            DestinationState::Update => "✓".green(),
+            DestinationState::UpdateMetadata => "✓".green(),
+            DestinationState::Delete => "✕".red(),
+        };
+        let message = match self {
+            DestinationState::Create => "create 📦️️".normal(),
+            DestinationState::Update => "update ✏️️".normal(),
+            DestinationState::UpdateMetadata => "update metadata ✏️️".normal(),
+            DestinationState::Delete => "delete 🗑️".normal(),
+        };
+        let file_message = match self {
+            DestinationState::Create => file_message.bright_green(),
+            DestinationState::Update => file_message.bright_green(),
+            DestinationState::UpdateMetadata => file_message.bright_green(),
+            DestinationState::Delete => file_message.red(),
+        };
+
+        format!("▕  {} ▕  {}  ▕  {} ", file_message, icon, message)
+    }
+
+    fn from_kind(kind: &SyncActionKind) -> Option<Self> {
+        match kind {
+            SyncActionKind::Create => Some(DestinationState::Create),
+            SyncActionKind::Update => Some(DestinationState::Update),
+            SyncActionKind::UpdateMetadata => Some(DestinationState::UpdateMetadata),
+            SyncActionKind::Delete => Some(DestinationState::Delete),
+            _ => None,
+        }
+    }
+}
+
+struct Reporter<'a> {
+    engine: &'a SyncEngine<'a>,
+    file_message_len_max: usize,
+}
+
+impl<'a> Reporter<'a> {
+    fn source_file_message(&self, action: &SyncAction) -> Result<String> {
+        self.engine.source().display_path(
+            &action.server_name,
+            &action.target,
+            &action.relative_path,
+        )
+    }
+
+    fn destination_file_message(&self, action: &SyncAction) -> Result<String> {
+        let path = self.engine.destination().display_path(
+            &action.server_name,
+            &action.target,
+            &action.relative_path,
+        )?;
+        Ok(format!("└─> {}", path))
+    }
+
+    fn new(engine: &'a SyncEngine<'a>, actions: &[SyncAction]) -> Result<Self> {
+        let mut reporter = Reporter {
+            engine,
+            file_message_len_max: 0,
+        };
+
+        let source_prefix_len_max = actions
+            .iter()
+            .map(|action| Ok(reporter.source_file_message(action)?.len()))
+            .collect::<Result<Vec<usize>>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+
+        let destination_prefix_len_max = actions
+            .iter()
+            .map(|action| Ok(reporter.destination_file_message(action)?.len() + 4))
+            .collect::<Result<Vec<usize>>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+
+        reporter.file_message_len_max = max(source_prefix_len_max, destination_prefix_len_max);
+        Ok(reporter)
+    }
+
+    fn messages(&self, action: &SyncAction) -> Result<Vec<String>> {
+        let mut messages = vec![];
+
+        if !action.is_source_hidden {
+            let file_message = self.source_file_message(action)?;
+            let file_message_len_diff = self.file_message_len_max - file_message.len();
+            let file_message = format!("{}{}", file_message, " ".repeat(file_message_len_diff));
+            messages.push(SourceState::from(&action.kind).message(&file_message));
+        }
+
+        if let Some(state) = DestinationState::from_kind(&action.kind) {
+            let file_message = self.destination_file_message(action)?;
+            let file_message_len_diff = self.file_message_len_max - file_message.len() + 4;
+            let file_message = format!("{}{}", file_message, " ".repeat(file_message_len_diff));
+            messages.push(state.message(&file_message));
+        }
+
+        Ok(messages)
+    }
+}
+
+/// Plan the synchronization of the stores and print it, applying it unless `dry_run` is set.
+/// With `delete`, files missing on the source side are deleted for every target.
+pub async fn sync_stores(
+    config: &CliConfig,
+    direction: SyncDirection,
+    local_client: &dyn ConfigStore,
+    remote_client: &dyn ConfigStore,
+    dry_run: bool,
+    delete: bool,
+    target_config_path: Option<&str>,
+) -> Result<()> {
+    let engine = SyncEngine::new(config, direction, local_client, remote_client);
+    let plan = engine.plan(target_config_path, delete).await?;
+    let reporter = Reporter::new(&engine, &plan.actions)?;
+
+    for warning in &plan.warnings {
+        println!("▕  {} ", warning.yellow());
+    }
+
+    let print_messages = |action: &SyncAction| {
+        for message in reporter.messages(action)? {
+            println!("{}", message);
+        }
+        Ok(())
+    };
+
+    if dry_run {
+        for action in &plan.actions {
+            print_messages(action)?;
+        }
+        return Ok(());
+    }
+
+    engine.execute(&plan, print_messages).await
+}
+
+/// Print the result of connecting to each server. Successful connections are only printed
+/// with `verbose`.
+pub fn print_connections(remote_client: &RemoteConfigClient, verbose: bool) {
+    for connection in remote_client.connections() {
+        let elapsed = format!("[{:.3}s]", connection.elapsed.as_secs_f64());
+        if let Some(error) = &connection.error {
+            println!(
+                "▕  {} ▕  {}  ▕  {} ",
+                connection.server_name.red(),
+                "✕".red(),
+                format!("unreachable ({:#}) {}", error, elapsed).normal()
+            );
+        } else if verbose {
+            println!(
+                "▕  {} ▕  {}  ▕  {} ",
+                connection.server_name.normal(),
+                "✓".green(),
+                format!("connected {}", elapsed).normal()
+            );
+        }
+    }
+}
+
+/// Fail when servers were skipped, so that the exit status reflects the partial run.
+pub fn ensure_reachable(remote_client: &RemoteConfigClient) -> Result<()> {
+    let server_names = remote_client
+        .unreachable_servers()
+        .map(|connection| &connection.server_name)
+        .join(", ");
+    if server_names.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Skipped unreachable servers. (servers={})",
+        server_names
+    ))
+}
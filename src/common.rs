@@ -1,7 +1,12 @@
+use crate::client::GlobMatch;
 use crate::config::{CliConfig, TargetConfig};
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use itertools::Itertools;
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 
 pub fn join_path(parent_path: &Path, path: &Path) -> PathBuf {
     if path == Path::new("") {
@@ -10,11 +15,140 @@ pub fn join_path(parent_path: &Path, path: &Path) -> PathBuf {
     parent_path.join(path)
 }
 
-pub fn convert_to_string(path: &Path) -> Result<String> {
-    path.to_owned()
-        .into_os_string()
-        .into_string()
-        .map_err(|os_string| anyhow!("Failed to string path. (os_string={:#?})", os_string))
+/// Name used for `~` locally when the remote user is resolved by ssh rather than configured.
+const UNKNOWN_USER: &str = "_";
+
+/// Path of a target relative to the local directory of a server. A leading `~` or `~user` is
+/// placed under `home/{user}`, where `user` is the remote user for `~`, or `_` when it is not
+/// configured.
+pub fn local_target_path(target_path: &str, user: Option<&str>) -> PathBuf {
+    if let Some(path) = target_path.strip_prefix('~') {
+        let (name, path) = path.split_once('/').unwrap_or((path, ""));
+        let name = if name.is_empty() {
+            user.unwrap_or(UNKNOWN_USER)
+        } else {
+            name
+        };
+        return join_path(&Path::new("home").join(name), Path::new(path));
+    }
+    PathBuf::from(target_path.trim_start_matches('/'))
+}
+
+/// Include and exclude globs of a target, matched against the path relative to the target and
+/// its parent directories. A pattern without `/` matches a name in any directory.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+        let pattern = if pattern.contains('/') {
+            pattern.to_owned()
+        } else {
+            format!("**/{}", pattern)
+        };
+        let glob = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob pattern. (pattern={})", pattern))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+fn matches(set: &GlobSet, relative_path: &Path) -> bool {
+    relative_path.ancestors().any(|path| set.is_match(path))
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(glob_set(include)?)
+        };
+        Ok(PathFilter {
+            include,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Whether every path matches.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    pub fn is_match(&self, relative_path: &Path) -> bool {
+        // The path of a file target is empty, and the target itself is always synced.
+        if relative_path == Path::new("") {
+            return true;
+        }
+        if self.is_excluded(relative_path) {
+            return false;
+        }
+        self.include
+            .as_ref()
+            .map_or(true, |include| matches(include, relative_path))
+    }
+
+    /// Whether the path or one of its parent directories is excluded.
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        matches(&self.exclude, relative_path)
+    }
+}
+
+/// Whether a component of `path` starts with `.` where the component of `pattern` at the same
+/// depth does not. The shell does not let a wildcard match a leading `.`, so neither does the
+/// local expansion.
+fn is_hidden_from(pattern: &str, path: &Path) -> bool {
+    pattern
+        .split('/')
+        .zip(path.components())
+        .any(|(pattern, component)| {
+            component.as_os_str().as_bytes().starts_with(b".") && !pattern.starts_with('.')
+        })
+}
+
+/// Paths matched by the glob of `target`, given the files under its `glob_base` relative to it.
+/// A directory matches through the files it contains.
+pub fn match_glob(target: &TargetConfig, relative_paths: &[PathBuf]) -> Result<Vec<GlobMatch>> {
+    let glob = GlobBuilder::new(target.glob_pattern())
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid glob pattern. (path={})", target.path))?
+        .compile_matcher();
+    let mut matches = BTreeMap::new();
+    for path in relative_paths {
+        for (idx, ancestor) in path.ancestors().enumerate() {
+            if ancestor != Path::new("")
+                && glob.is_match(ancestor)
+                && !is_hidden_from(target.glob_pattern(), ancestor)
+            {
+                matches.insert(ancestor, idx >= 1);
+            }
+        }
+    }
+    Ok(matches
+        .into_iter()
+        .map(|(relative_path, is_dir)| GlobMatch {
+            relative_path: relative_path.to_owned(),
+            is_dir,
+        })
+        .collect())
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Drop `.` components, so that `./config/x` and `config/x` compare equal.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
 }
 
 pub fn is_target_config(
@@ -28,6 +162,12 @@ pub fn is_target_config(
         .iter()
         .map(|server| format!("{}/{}", cli_config.local.config_root_path, server.name()))
         .collect_vec();
+    for role in config.roles.iter().flatten() {
+        prefixes.push(format!(
+            "{}/roles/{}",
+            cli_config.local.config_root_path, role
+        ));
+    }
     prefixes.push(cli_config.local.config_root_path.clone());
     prefixes.push(format!("{}/", cli_config.local.config_root_path.clone()));
 
@@ -41,5 +181,70 @@ pub fn is_target_config(
     if config.path == target_config_path {
         return true;
     }
+    // Targets under a home directory are placed locally under `home/{user}`.
+    let target_config_path = Path::new(target_config_path);
+    let root = Path::new(&cli_config.local.config_root_path);
+    for server in &cli_config.remote.servers {
+        let user = config.home_user(server, &cli_config.remote);
+        let path = local_target_path(&config.path, user);
+        let prefix = join_path(root, &config.local_dir(&server.name()));
+        if normalize(&join_path(&prefix, &path)) == normalize(target_config_path) {
+            return true;
+        }
+    }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_target_path_places_home_directories_under_home() {
+        assert_eq!(
+            local_target_path("/etc/nginx", Some("isucon")),
+            Path::new("etc/nginx")
+        );
+        assert_eq!(
+            local_target_path("~/webapp", Some("isucon")),
+            Path::new("home/isucon/webapp")
+        );
+        assert_eq!(
+            local_target_path("~", Some("isucon")),
+            Path::new("home/isucon")
+        );
+        assert_eq!(
+            local_target_path("~mysql/my.cnf", Some("isucon")),
+            Path::new("home/mysql/my.cnf")
+        );
+        assert_eq!(
+            local_target_path("~/webapp", None),
+            Path::new("home/_/webapp")
+        );
+    }
+
+    #[test]
+    fn match_glob_does_not_match_a_leading_dot_with_a_wildcard() {
+        let paths = [
+            ".hidden.conf",
+            "a.conf",
+            "conf.d/.b.conf",
+            "conf.d/c.conf",
+            ".git/d.conf",
+        ]
+        .map(PathBuf::from);
+        let matched = |path: &str| {
+            let target: TargetConfig = serde_yaml::from_str(&format!("path: {}", path)).unwrap();
+            match_glob(&target, &paths)
+                .unwrap()
+                .into_iter()
+                .map(|glob_match| glob_match.relative_path.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matched("/etc/nginx/*.conf"), vec!["a.conf"]);
+        assert_eq!(matched("/etc/nginx/.*.conf"), vec![".hidden.conf"]);
+        assert_eq!(matched("/etc/nginx/*/*.conf"), vec!["conf.d/c.conf"]);
+        assert_eq!(matched("/etc/nginx/*"), vec!["a.conf", "conf.d"]);
+        assert_eq!(matched("/etc/nginx/.*/*.conf"), vec![".git/d.conf"]);
+    }
+}
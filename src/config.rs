@@ -1,5 +1,8 @@
-use anyhow::{Context, Result};
+use crate::common::PathFilter;
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
 use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
 fn default_as_true() -> bool {
@@ -13,21 +16,164 @@ fn default_as_false() -> bool {
 #[derive(Deserialize, Clone)]
 pub struct ServerConfig {
     pub alias: Option<String>,
-    pub host: String,
+    pub host: Option<String>,
+    /// Host entry of the user's ssh config to connect to.
+    pub ssh_host: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity: Option<String>,
+    pub proxy_jump: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
 }
 
 impl ServerConfig {
     pub fn name(&self) -> String {
-        self.alias.to_owned().unwrap_or_else(|| self.host.clone())
+        self.alias
+            .as_ref()
+            .or(self.ssh_host.as_ref())
+            .or(self.host.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether ssh resolves the connection settings from the user's ssh config.
+    pub fn uses_ssh_config(&self, remote: &RemoteConfig) -> bool {
+        self.ssh_host.is_some() || remote.use_ssh_config
+    }
+
+    /// Destination passed to ssh. With `use_ssh_config`, the alias names the ssh config entry.
+    pub fn destination<'a>(&'a self, remote: &RemoteConfig) -> Result<&'a str> {
+        let alias = if remote.use_ssh_config {
+            self.alias.as_deref()
+        } else {
+            None
+        };
+        self.ssh_host
+            .as_deref()
+            .or(alias)
+            .or(self.host.as_deref())
+            .with_context(|| format!("Not found host. (server={})", self.name()))
+    }
+
+    /// Remote config whose settings apply to the server as defaults. A server resolved from the
+    /// user's ssh config takes them from its entry there instead.
+    fn defaults<'a>(&self, remote: &'a RemoteConfig) -> Option<&'a RemoteConfig> {
+        if self.uses_ssh_config(remote) {
+            None
+        } else {
+            Some(remote)
+        }
+    }
+
+    pub fn user<'a>(&'a self, remote: &'a RemoteConfig) -> Option<&'a str> {
+        self.user
+            .as_deref()
+            .or_else(|| self.defaults(remote)?.user.as_deref())
+    }
+
+    pub fn port(&self, remote: &RemoteConfig) -> Option<u16> {
+        self.port.or_else(|| self.defaults(remote)?.port)
+    }
+
+    pub fn identity<'a>(&'a self, remote: &'a RemoteConfig) -> Option<&'a str> {
+        self.identity
+            .as_deref()
+            .or_else(|| self.defaults(remote)?.identity.as_deref())
+    }
+
+    pub fn proxy_jump<'a>(&'a self, remote: &'a RemoteConfig) -> Option<&'a str> {
+        self.proxy_jump
+            .as_deref()
+            .or_else(|| self.defaults(remote)?.proxy_jump.as_deref())
     }
 }
 
+/// How the host key of a server is verified.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KnownHostsPolicy {
+    /// The host key must already be in the known hosts file.
+    Strict,
+    /// Unknown host keys are added, changed host keys are rejected.
+    Add,
+    /// Any host key is accepted.
+    #[default]
+    Accept,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct RemoteConfig {
     pub servers: Vec<ServerConfig>,
-    pub user: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
     pub identity: Option<String>,
+    pub proxy_jump: Option<String>,
+    #[serde(default)]
+    pub known_hosts: KnownHostsPolicy,
+    pub known_hosts_file: Option<String>,
     pub timeout: Option<u64>,
+    pub control_directory: Option<String>,
+    #[serde(default = "default_as_false")]
+    pub skip_unreachable: bool,
+    #[serde(default = "default_as_false")]
+    pub use_ssh_config: bool,
+    /// `sudo`, `sudo -n`, `doas`, or a template with `{command}` and `{user}`.
+    pub escalation: Option<String>,
+}
+
+/// Command that runs remote commands as another user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Escalation {
+    Sudo,
+    /// `sudo -n`, which fails instead of asking for a password.
+    SudoNonInteractive,
+    Doas,
+    /// A command line where `{user}` is replaced with the user and `{command}` with the command
+    /// to run, quoted as a single shell word.
+    Template(String),
+}
+
+impl RemoteConfig {
+    pub fn escalation(&self) -> Result<Escalation> {
+        match self.escalation.as_deref() {
+            None | Some("sudo") => Ok(Escalation::Sudo),
+            Some("sudo -n") => Ok(Escalation::SudoNonInteractive),
+            Some("doas") => Ok(Escalation::Doas),
+            Some(template) if template.contains("{command}") => {
+                Ok(Escalation::Template(template.to_owned()))
+            }
+            Some(escalation) => Err(anyhow!(
+                "Invalid escalation. Use sudo, sudo -n, doas or a template with {{command}}. (escalation={})",
+                escalation
+            )),
+        }
+    }
+
+    pub fn server(&self, server_name: &str) -> Result<&ServerConfig> {
+        self.servers
+            .iter()
+            .find(|server| server.name() == server_name)
+            .with_context(|| format!("Not found server. (server={})", server_name))
+    }
+
+    /// Keep only the servers with `role`.
+    pub fn retain_role(&mut self, role: &str) -> Result<()> {
+        self.servers
+            .retain(|server| server.roles.iter().any(|server_role| server_role == role));
+        if self.servers.is_empty() {
+            return Err(anyhow!("Not found server with role. (role={})", role));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for server in &self.servers {
+            server.destination(self)?;
+        }
+        self.escalation()?;
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -46,6 +192,113 @@ pub struct TargetConfig {
     pub sudo: bool,
     #[serde(default = "default_as_false")]
     pub shared: bool,
+    /// Names of the servers the target applies to. Every server when omitted.
+    pub servers: Option<Vec<String>>,
+    /// Roles of the servers the target applies to. Every server when omitted.
+    pub roles: Option<Vec<String>>,
+    /// User to operate the remote files as, through `sudo -u`.
+    #[serde(rename = "become")]
+    pub become_user: Option<String>,
+    /// Globs of the files to sync. Every file is synced when empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of the files not to sync. The patterns of `.isuconfignore` are added on load.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Delete files that do not exist on the source side.
+    #[serde(default = "default_as_false")]
+    pub delete: bool,
+    /// Transfer the files symlinks point to instead of the symlinks.
+    #[serde(default = "default_as_false")]
+    pub follow_symlinks: bool,
+    /// Octal permission bits applied on push, such as `"0644"`.
+    pub mode: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+/// Characters that make a component of a target path a glob.
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+impl TargetConfig {
+    pub fn is_glob(&self) -> bool {
+        self.path.contains(GLOB_CHARS)
+    }
+
+    /// Directory of a glob path before the first component with a glob, such as `/etc/nginx`
+    /// for `/etc/nginx/*/*.conf`.
+    pub fn glob_base(&self) -> &str {
+        let end = self.path.find(GLOB_CHARS).unwrap_or(self.path.len());
+        let base = &self.path[..self.path[..end].rfind('/').unwrap_or(0)];
+        if base.is_empty() && self.path.starts_with('/') {
+            "/"
+        } else {
+            base
+        }
+    }
+
+    /// Glob of a glob path relative to `glob_base`.
+    pub fn glob_pattern(&self) -> &str {
+        self.path[self.glob_base().len()..].trim_start_matches('/')
+    }
+
+    /// Target path of a path relative to `glob_base`, or `None` when it is not UTF-8.
+    pub fn glob_path(&self, relative_path: &Path) -> Option<String> {
+        Some(format!(
+            "{}/{}",
+            self.glob_base().trim_end_matches('/'),
+            relative_path.to_str()?
+        ))
+    }
+
+    pub fn path_filter(&self) -> Result<PathFilter> {
+        PathFilter::new(&self.include, &self.exclude)
+            .with_context(|| format!("Invalid include or exclude. (path={})", self.path))
+    }
+
+    pub fn applies_to(&self, server: &ServerConfig) -> bool {
+        let has_server = self
+            .servers
+            .as_ref()
+            .map_or(true, |servers| servers.contains(&server.name()));
+        let has_role = self.roles.as_ref().map_or(true, |roles| {
+            roles.iter().any(|role| server.roles.contains(role))
+        });
+        has_server && has_role
+    }
+
+    /// Directory of the target relative to `config_root_path`: the server name, or for a shared
+    /// target, `roles/{role}` when it has a role and the root itself otherwise.
+    pub fn local_dir(&self, server_name: &str) -> PathBuf {
+        if !self.shared {
+            return PathBuf::from(server_name);
+        }
+        match self.roles.as_deref() {
+            Some([role]) => Path::new("roles").join(role),
+            _ => PathBuf::new(),
+        }
+    }
+
+    /// User whose home `~` refers to: the `become` user, or else the remote user.
+    pub fn home_user<'a>(
+        &'a self,
+        server: &'a ServerConfig,
+        remote: &'a RemoteConfig,
+    ) -> Option<&'a str> {
+        self.become_user.as_deref().or(server.user(remote))
+    }
+
+    pub fn mode(&self) -> Result<Option<u32>> {
+        self.mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .with_context(|| format!("Invalid mode. (path={}, mode={})", self.path, mode))
+            })
+            .transpose()
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -58,6 +311,10 @@ pub struct CliConfig {
 }
 
 impl CliConfig {
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(10)
+    }
+
     pub fn max_file_size(&self) -> Result<u64> {
         Ok(self
             .max_file_size
@@ -70,6 +327,83 @@ impl CliConfig {
             .transpose()?
             .unwrap_or(300 * 1024))
     }
+
+    fn validate(&self) -> Result<()> {
+        if self.concurrency() == 0 {
+            return Err(anyhow!(
+                "Invalid concurrency. Use 1 or more. (concurrency={})",
+                self.concurrency()
+            ));
+        }
+        self.remote.validate()?;
+        for target in &self.targets {
+            target.mode()?;
+            target.path_filter()?;
+            if let (Some(_), Escalation::Template(template)) =
+                (&target.become_user, self.remote.escalation()?)
+            {
+                if !template.contains("{user}") {
+                    return Err(anyhow!(
+                        "Invalid escalation. A template without {{user}} cannot run as the become user. (path={}, escalation={})",
+                        target.path,
+                        template
+                    ));
+                }
+            }
+            for server_name in target.servers.iter().flatten() {
+                self.remote
+                    .server(server_name)
+                    .with_context(|| format!("Invalid servers. (path={})", target.path))?;
+            }
+            let roles = target.roles.iter().flatten().collect_vec();
+            if target.shared && roles.len() > 1 {
+                return Err(anyhow!(
+                    "Invalid roles. A shared target takes a single role. (path={})",
+                    target.path
+                ));
+            }
+            for role in roles {
+                // A role names the local directory `roles/{role}` of a shared target.
+                if role.is_empty() || role.contains('/') || role == "." || role == ".." {
+                    return Err(anyhow!(
+                        "Invalid role. Use a name without `/`. (path={}, role={})",
+                        target.path,
+                        role
+                    ));
+                }
+                if !self
+                    .remote
+                    .servers
+                    .iter()
+                    .any(|server| server.roles.contains(role))
+                {
+                    return Err(anyhow!(
+                        "Not found server with role. (path={}, role={})",
+                        target.path,
+                        role
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// File under `config_root_path` with glob patterns excluded from every target, one per line.
+const IGNORE_FILE_NAME: &str = ".isuconfignore";
+
+async fn read_ignore_patterns(config: &LocalConfig) -> Result<Vec<String>> {
+    let path = Path::new(&config.config_root_path).join(IGNORE_FILE_NAME);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let patterns = fs::read_to_string(&path).await?;
+    Ok(patterns
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
 }
 
 pub async fn read_config(config_path: &str) -> Result<CliConfig> {
@@ -79,7 +413,143 @@ pub async fn read_config(config_path: &str) -> Result<CliConfig> {
             &config_path
         )
     })?;
-    let config = serde_yaml::from_str(&json)
+    let mut config: CliConfig = serde_yaml::from_str(&json)
+        .with_context(|| format!("Invalid config file. (config_path={})", config_path))?;
+    let ignore_patterns = read_ignore_patterns(&config.local).await?;
+    for target in &mut config.targets {
+        target.exclude.extend(ignore_patterns.iter().cloned());
+    }
+    config
+        .validate()
         .with_context(|| format!("Invalid config file. (config_path={})", config_path))?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_defaults_do_not_apply_to_ssh_config_servers() {
+        let remote: RemoteConfig = serde_yaml::from_str(
+            r#"
+servers:
+  - host: xx.xx.xx.xx
+  - ssh_host: is2
+  - ssh_host: is3
+    user: isucon
+user: ubuntu
+port: 2222
+identity: ~/.ssh/isucon.pem
+proxy_jump: bastion
+"#,
+        )
+        .unwrap();
+        let server = &remote.servers[0];
+        assert_eq!(server.user(&remote), Some("ubuntu"));
+        assert_eq!(server.port(&remote), Some(2222));
+        assert_eq!(server.identity(&remote), Some("~/.ssh/isucon.pem"));
+        assert_eq!(server.proxy_jump(&remote), Some("bastion"));
+        let server = &remote.servers[1];
+        assert_eq!(server.user(&remote), None);
+        assert_eq!(server.port(&remote), None);
+        assert_eq!(server.identity(&remote), None);
+        assert_eq!(server.proxy_jump(&remote), None);
+        assert_eq!(remote.servers[2].user(&remote), Some("isucon"));
+    }
+
+    #[test]
+    fn become_requires_user_in_escalation_template() {
+        let yaml = |escalation: &str| {
+            format!(
+                r#"
+remote:
+  servers:
+    - host: is1
+  escalation: "{}"
+local:
+  config_root_path: ./config
+targets:
+  - path: /home/isucon/env.sh
+    become: isucon
+"#,
+                escalation
+            )
+        };
+        let config: CliConfig = serde_yaml::from_str(&yaml("su -c {command}")).unwrap();
+        assert!(config.validate().is_err());
+        let config: CliConfig = serde_yaml::from_str(&yaml("su {user} -c {command}")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    fn roles_config(targets: &str) -> CliConfig {
+        let yaml = format!(
+            r#"
+remote:
+  servers:
+    - host: is1
+      roles: [app]
+    - host: is2
+      roles: [app, db]
+    - host: is3
+      roles: [db]
+local:
+  config_root_path: ./config
+targets:
+{}"#,
+            targets
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn roles_select_servers_and_local_directory() {
+        let config = roles_config("  - path: /etc/mysql\n    roles: [db]\n    shared: true\n");
+        assert!(config.validate().is_ok());
+        let target = &config.targets[0];
+        let names = config
+            .remote
+            .servers
+            .iter()
+            .filter(|server| target.applies_to(server))
+            .map(|server| server.name())
+            .collect_vec();
+        assert_eq!(names, vec!["is2", "is3"]);
+        assert_eq!(target.local_dir("is2"), Path::new("roles/db"));
+
+        let config = roles_config("  - path: /etc/nginx\n    roles: [app]\n");
+        assert_eq!(config.targets[0].local_dir("is1"), Path::new("is1"));
+    }
+
+    #[test]
+    fn retain_role_keeps_servers_with_the_role() {
+        let mut remote = roles_config("").remote;
+        remote.retain_role("db").unwrap();
+        let names = remote
+            .servers
+            .iter()
+            .map(|server| server.name())
+            .collect_vec();
+        assert_eq!(names, vec!["is2", "is3"]);
+        assert!(remote.retain_role("bench").is_err());
+    }
+
+    #[test]
+    fn invalid_roles_are_rejected() {
+        for targets in [
+            "  - path: /etc/mysql\n    roles: [app, db]\n    shared: true\n",
+            "  - path: /etc/mysql\n    roles: [bench]\n",
+        ] {
+            assert!(roles_config(targets).validate().is_err(), "{}", targets);
+        }
+        for role in ["../db", "db/x", "..", ""] {
+            let mut config = roles_config("  - path: /etc/mysql\n    shared: true\n");
+            config.remote.servers[2].roles.push(role.to_owned());
+            config.targets[0].roles = Some(vec![role.to_owned()]);
+            let error = config.validate().unwrap_err().to_string();
+            assert!(error.starts_with("Invalid role."), "{}", error);
+        }
+        let config = roles_config("  - path: /etc/mysql\n    roles: [app, db]\n");
+        assert!(config.validate().is_ok());
+    }
+}
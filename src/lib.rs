@@ -2,3 +2,6 @@ pub mod client;
 pub mod commands;
 pub mod common;
 pub mod config;
+pub mod sync;
+
+pub use crate::sync::*;
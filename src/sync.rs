@@ -0,0 +1,4 @@
+pub use self::{engine::*, plan::*};
+
+pub mod engine;
+pub mod plan;
@@ -0,0 +1,890 @@
+use crate::client::{is_target_config, ConfigStore, FileEntry, FileMetadata};
+use crate::config::{CliConfig, ServerConfig, TargetConfig};
+use crate::sync::{SyncAction, SyncActionKind, SyncDirection, SyncPlan};
+use anyhow::Result;
+use futures::StreamExt;
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+
+/// Decide the action for a source file from the destination file at the same path, if any, and
+/// the metadata the destination file should have.
+fn compare(
+    source: &FileEntry,
+    destination: Option<&FileEntry>,
+    metadata: &FileMetadata,
+) -> SyncActionKind {
+    match destination {
+        None => SyncActionKind::Create,
+        Some(destination) if source.link_target.is_some() || destination.link_target.is_some() => {
+            if source.link_target == destination.link_target {
+                SyncActionKind::Synced
+            } else {
+                SyncActionKind::Update
+            }
+        }
+        Some(destination)
+            if source.hash.is_none()
+                || source.size != destination.size
+                || source.hash != destination.hash =>
+        {
+            SyncActionKind::Update
+        }
+        Some(destination) if !metadata.is_satisfied_by(&destination.metadata) => {
+            SyncActionKind::UpdateMetadata
+        }
+        Some(_) => SyncActionKind::Synced,
+    }
+}
+
+/// Plans and executes the synchronization between a local and a remote store.
+pub struct SyncEngine<'a> {
+    config: &'a CliConfig,
+    direction: SyncDirection,
+    local_client: &'a dyn ConfigStore,
+    remote_client: &'a dyn ConfigStore,
+}
+
+impl<'a> SyncEngine<'a> {
+    pub fn new(
+        config: &'a CliConfig,
+        direction: SyncDirection,
+        local_client: &'a dyn ConfigStore,
+        remote_client: &'a dyn ConfigStore,
+    ) -> Self {
+        SyncEngine {
+            config,
+            direction,
+            local_client,
+            remote_client,
+        }
+    }
+
+    pub fn direction(&self) -> SyncDirection {
+        self.direction
+    }
+
+    pub fn source(&self) -> &'a dyn ConfigStore {
+        match self.direction {
+            SyncDirection::Push => self.local_client,
+            SyncDirection::Pull => self.remote_client,
+        }
+    }
+
+    pub fn destination(&self) -> &'a dyn ConfigStore {
+        match self.direction {
+            SyncDirection::Push => self.remote_client,
+            SyncDirection::Pull => self.local_client,
+        }
+    }
+
+    fn is_enabled(&self, target: &TargetConfig) -> bool {
+        match self.direction {
+            SyncDirection::Push => target.push,
+            SyncDirection::Pull => target.pull,
+        }
+    }
+
+    /// Metadata the destination file should have. On push, the metadata set in the target
+    /// overrides the metadata recorded on pull.
+    fn metadata(&self, target: &TargetConfig, source: &FileEntry) -> Result<FileMetadata> {
+        let mut metadata = source.metadata.clone();
+        if self.direction == SyncDirection::Push {
+            metadata.merge(&FileMetadata {
+                mode: target.mode()?,
+                owner: target.owner.clone(),
+                group: target.group.clone(),
+            });
+        }
+        Ok(metadata)
+    }
+
+    /// Build the plan for every target, or only for `target_config_path` when given. Files are
+    /// compared by the hashes of the inventories, so nothing is transferred while planning.
+    /// With `delete`, files missing on the source side are deleted from every target, otherwise
+    /// only from the targets with `delete` set.
+    pub async fn plan(&self, target_config_path: Option<&str>, delete: bool) -> Result<SyncPlan> {
+        let mut actions = vec![];
+        let mut warnings = vec![];
+
+        for target in &self.config.targets {
+            let is_selected = target_config_path.map_or(true, |target_config_path| {
+                is_target_config(self.config, target, target_config_path)
+            });
+            for target in self.expand(target, &mut warnings).await? {
+                if let Some(target_config_path) = target_config_path {
+                    if !is_selected && !is_target_config(self.config, &target, target_config_path) {
+                        continue;
+                    }
+                }
+                actions.append(&mut self.plan_target(&target, delete).await?);
+                // A path given explicitly reports the servers the target leaves out, so a
+                // target for other servers is not mistaken for a missing one.
+                if target_config_path.is_some() {
+                    actions.append(&mut self.not_applicable(&target));
+                }
+            }
+        }
+
+        Ok(SyncPlan {
+            direction: self.direction,
+            actions,
+            warnings,
+        })
+    }
+
+    /// Servers the target applies to that both stores can operate.
+    fn servers(&self, target: &TargetConfig) -> Vec<&ServerConfig> {
+        self.config
+            .remote
+            .servers
+            .iter()
+            .filter(|server| {
+                target.applies_to(server)
+                    && self.local_client.is_available(&server.name())
+                    && self.remote_client.is_available(&server.name())
+            })
+            .collect_vec()
+    }
+
+    /// Actions for the servers both stores can operate that the target does not apply to.
+    fn not_applicable(&self, target: &TargetConfig) -> Vec<SyncAction> {
+        self.config
+            .remote
+            .servers
+            .iter()
+            .filter(|server| {
+                !target.applies_to(server)
+                    && self.local_client.is_available(&server.name())
+                    && self.remote_client.is_available(&server.name())
+            })
+            .map(|server| SyncAction {
+                kind: SyncActionKind::NotApplicable,
+                server_name: server.name(),
+                target: target.to_owned(),
+                relative_path: PathBuf::new(),
+                metadata: FileMetadata::default(),
+                link_target: None,
+                is_source_hidden: false,
+            })
+            .collect()
+    }
+
+    /// Expand a target whose path is a glob into a target for each path it matches on the
+    /// source side of any server. A target without glob, or without matches, is kept as is.
+    /// Matches are filtered by include and exclude relative to the `glob_base`, and matches
+    /// that are not UTF-8 are skipped with a warning.
+    /// A path matched only on the destination side is not expanded, so it is never deleted, as
+    /// with any target that does not exist on the source side.
+    async fn expand(
+        &self,
+        target: &TargetConfig,
+        warnings: &mut Vec<String>,
+    ) -> Result<Vec<TargetConfig>> {
+        if !target.is_glob() || !self.is_enabled(target) {
+            return Ok(vec![target.to_owned()]);
+        }
+        let filter = target.path_filter()?;
+        let mut paths = BTreeSet::new();
+        for server in self.servers(target) {
+            for glob_match in self.source().expand(&server.name(), target).await? {
+                // The files in a directory are filtered once it is expanded into a target.
+                let is_match = if glob_match.is_dir {
+                    !filter.is_excluded(&glob_match.relative_path)
+                } else {
+                    filter.is_match(&glob_match.relative_path)
+                };
+                if !is_match {
+                    continue;
+                }
+                match target.glob_path(&glob_match.relative_path) {
+                    Some(path) => {
+                        paths.insert(path);
+                    }
+                    None => warnings.push(format!(
+                        "Skipped a path matched by glob that is not UTF-8. (server={}, path={}, matched={:?})",
+                        server.name(),
+                        target.path,
+                        glob_match.relative_path
+                    )),
+                }
+            }
+        }
+        if paths.is_empty() {
+            return Ok(vec![target.to_owned()]);
+        }
+        Ok(paths
+            .into_iter()
+            .map(|path| TargetConfig {
+                path,
+                ..target.to_owned()
+            })
+            .collect())
+    }
+
+    async fn plan_target(&self, target: &TargetConfig, delete: bool) -> Result<Vec<SyncAction>> {
+        let mut actions = vec![];
+
+        let mut entries_by_path: BTreeMap<PathBuf, Vec<(String, FileEntry)>> = BTreeMap::new();
+        let mut destination_entries: HashMap<String, BTreeMap<PathBuf, FileEntry>> = HashMap::new();
+        let mut destination_servers = vec![];
+
+        let max_file_size = self.config.max_file_size()?;
+        let servers = self.servers(target);
+
+        for (idx, server) in servers.into_iter().enumerate() {
+            // Every server shares the same local config, so pulling it once is enough.
+            if self.direction == SyncDirection::Pull && idx >= 1 && target.shared {
+                continue;
+            }
+            let is_source_hidden = idx >= 1 && target.shared;
+            if !self.is_enabled(target) {
+                actions.push(SyncAction {
+                    kind: SyncActionKind::Skip,
+                    server_name: server.name(),
+                    target: target.to_owned(),
+                    relative_path: PathBuf::new(),
+                    metadata: FileMetadata::default(),
+                    link_target: None,
+                    is_source_hidden,
+                });
+                continue;
+            }
+            let entries = self
+                .source()
+                .inventory(&server.name(), target, max_file_size)
+                .await?;
+            if entries.is_empty() {
+                actions.push(SyncAction {
+                    kind: SyncActionKind::NotExists,
+                    server_name: server.name(),
+                    target: target.to_owned(),
+                    relative_path: PathBuf::new(),
+                    metadata: FileMetadata::default(),
+                    link_target: None,
+                    is_source_hidden,
+                });
+                continue;
+            }
+            for entry in entries {
+                entries_by_path
+                    .entry(entry.relative_path.clone())
+                    .or_default()
+                    .push((server.name(), entry));
+            }
+            let entries = self
+                .destination()
+                .inventory(&server.name(), target, max_file_size)
+                .await?;
+            destination_servers.push(server.name());
+            destination_entries.insert(
+                server.name(),
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.relative_path.clone(), entry))
+                    .collect(),
+            );
+        }
+
+        // Files that only exist on the destination side, when the target has files on the
+        // source side. A target missing on the source side never deletes anything. Files over
+        // `max_file_size` are never transferred, so their absence on the source side does not
+        // mean they were removed.
+        let mut deleted_by_path: BTreeMap<PathBuf, Vec<(String, SyncActionKind)>> = BTreeMap::new();
+        if delete || target.delete {
+            for server_name in &destination_servers {
+                for (path, entry) in &destination_entries[server_name] {
+                    let exists = entries_by_path.get(path).map_or(false, |entries| {
+                        entries.iter().any(|(name, _)| name == server_name)
+                    });
+                    if exists {
+                        continue;
+                    }
+                    let kind = if entry.is_too_large(max_file_size) {
+                        SyncActionKind::TooLarge
+                    } else {
+                        SyncActionKind::Delete
+                    };
+                    deleted_by_path
+                        .entry(path.to_owned())
+                        .or_default()
+                        .push((server_name.to_owned(), kind));
+                }
+            }
+        }
+
+        for (path, entries) in entries_by_path {
+            for (idx, (server_name, entry)) in entries.into_iter().enumerate() {
+                let metadata = self.metadata(target, &entry)?;
+                let kind = if !entry.is_readable {
+                    SyncActionKind::Unreadable
+                } else if entry.is_too_large(max_file_size) {
+                    SyncActionKind::TooLarge
+                } else {
+                    let destination_entry = destination_entries
+                        .get(&server_name)
+                        .and_then(|entries| entries.get(&path));
+                    compare(&entry, destination_entry, &metadata)
+                };
+                actions.push(SyncAction {
+                    kind,
+                    server_name,
+                    target: target.to_owned(),
+                    relative_path: path.to_owned(),
+                    metadata,
+                    link_target: entry.link_target,
+                    is_source_hidden: idx >= 1 && target.shared,
+                });
+            }
+        }
+
+        for (path, server_names) in deleted_by_path {
+            for (idx, (server_name, kind)) in server_names.into_iter().enumerate() {
+                actions.push(SyncAction {
+                    kind,
+                    server_name,
+                    target: target.to_owned(),
+                    relative_path: path.to_owned(),
+                    metadata: FileMetadata::default(),
+                    link_target: None,
+                    is_source_hidden: idx >= 1 && target.shared,
+                });
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Apply a single action to the destination. Actions without a transfer are no-ops.
+    pub async fn execute_action(&self, action: &SyncAction) -> Result<()> {
+        match action.kind {
+            SyncActionKind::Create | SyncActionKind::Update => {
+                if let Some(link_target) = &action.link_target {
+                    return self
+                        .destination()
+                        .create_link(
+                            &action.server_name,
+                            &action.target,
+                            &action.relative_path,
+                            link_target,
+                        )
+                        .await;
+                }
+                let config = self
+                    .source()
+                    .get(&action.server_name, &action.target, &action.relative_path)
+                    .await?;
+                self.destination()
+                    .create(
+                        &action.server_name,
+                        &action.target,
+                        &action.relative_path,
+                        config,
+                        &action.metadata,
+                    )
+                    .await
+            }
+            SyncActionKind::UpdateMetadata => {
+                self.destination()
+                    .set_metadata(
+                        &action.server_name,
+                        &action.target,
+                        &action.relative_path,
+                        &action.metadata,
+                    )
+                    .await
+            }
+            SyncActionKind::Delete => {
+                self.destination()
+                    .remove(&action.server_name, &action.target, &action.relative_path)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply every action of the plan, calling `on_executed` in plan order as actions finish.
+    /// The destination is flushed even when an action fails, so that the applied actions are
+    /// kept.
+    pub async fn execute<F>(&self, plan: &SyncPlan, on_executed: F) -> Result<()>
+    where
+        F: FnMut(&SyncAction) -> Result<()>,
+    {
+        let executed = self.execute_actions(plan, on_executed).await;
+        let flushed = self.destination().flush().await;
+        executed.and(flushed)
+    }
+
+    async fn execute_actions<F>(&self, plan: &SyncPlan, mut on_executed: F) -> Result<()>
+    where
+        F: FnMut(&SyncAction) -> Result<()>,
+    {
+        for sub_actions in plan
+            .actions
+            .iter()
+            .chunks(self.config.concurrency())
+            .into_iter()
+        {
+            let mut stream = futures::stream::FuturesOrdered::new();
+
+            for action in sub_actions {
+                stream.push_back(async move {
+                    self.execute_action(action).await?;
+                    Ok::<_, anyhow::Error>(action)
+                });
+            }
+
+            while let Some(action) = stream.next().await {
+                on_executed(action?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{join_path, MemoryConfigClient};
+    use crate::sync::SyncPlan;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn config(targets: &str) -> CliConfig {
+        let yaml = format!(
+            r#"
+max_file_size: 16
+remote:
+  servers:
+    - host: is1
+    - host: is2
+local:
+  config_root_path: ./config
+targets:
+{}"#,
+            targets
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn kinds(plan: &SyncPlan) -> Vec<(SyncActionKind, &str, &str)> {
+        plan.actions
+            .iter()
+            .map(|action| {
+                (
+                    action.kind.clone(),
+                    action.server_name.as_str(),
+                    action.relative_path.to_str().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn push_creates_and_updates_files() {
+        let config = config("  - path: /etc/nginx\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        local.insert("is1", "/etc/nginx/sites/default", b"b".to_vec());
+        local.insert("is1", "/etc/nginx/new", b"n".to_vec());
+        remote.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        remote.insert("is1", "/etc/nginx/sites/default", b"old".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::Create, "is1", "new"),
+                (SyncActionKind::Synced, "is1", "nginx.conf"),
+                (SyncActionKind::Update, "is1", "sites/default"),
+            ]
+        );
+
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(remote.file("is1", "/etc/nginx/new"), Some(b"n".to_vec()));
+        assert_eq!(
+            remote.file("is1", "/etc/nginx/sites/default"),
+            Some(b"b".to_vec())
+        );
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(plan.transfers().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn push_updates_metadata_of_identical_files() {
+        let config = config("  - path: /etc/hosts\n    mode: \"0644\"\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/hosts", b"a".to_vec());
+        remote.insert("is1", "/etc/hosts", b"a".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::UpdateMetadata, "is1", ""),
+            ]
+        );
+
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(
+            remote.metadata("is1", "/etc/hosts").unwrap().mode,
+            Some(0o644)
+        );
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(plan.actions[1].kind, SyncActionKind::Synced);
+    }
+
+    #[tokio::test]
+    async fn pull_skips_too_large_files() {
+        let config = config("  - path: /var/log\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        remote.insert("is1", "/var/log/small", b"a".to_vec());
+        remote.insert("is1", "/var/log/large", vec![b'a'; 17]);
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::TooLarge, "is1", "large"),
+                (SyncActionKind::Create, "is1", "small"),
+            ]
+        );
+
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(local.file("is1", "/var/log/large"), None);
+        assert_eq!(local.file("is1", "/var/log/small"), Some(b"a".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn push_deletes_files_missing_locally() {
+        let config = config("  - path: /etc/nginx\n  - path: /etc/mysql\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        remote.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        remote.insert("is1", "/etc/nginx/removed.conf", b"r".to_vec());
+        remote.insert("is1", "/etc/mysql/my.cnf", b"m".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert!(plan.transfers().next().is_none());
+
+        let plan = engine.plan(None, true).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::Synced, "is1", "nginx.conf"),
+                (SyncActionKind::Delete, "is1", "removed.conf"),
+                (SyncActionKind::NotExists, "is1", ""),
+                (SyncActionKind::NotExists, "is2", ""),
+            ]
+        );
+
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(remote.file("is1", "/etc/nginx/removed.conf"), None);
+        // A target missing locally never deletes anything.
+        assert_eq!(remote.file("is1", "/etc/mysql/my.cnf"), Some(b"m".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn push_does_not_delete_files_too_large_to_pull() {
+        let config = config("  - path: /etc/nginx\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        remote.insert("is1", "/etc/nginx/nginx.conf", b"a".to_vec());
+        remote.insert("is1", "/etc/nginx/GeoIP.dat", vec![0; 17]);
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, true).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::Synced, "is1", "nginx.conf"),
+                (SyncActionKind::TooLarge, "is1", "GeoIP.dat"),
+            ]
+        );
+
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert!(remote.file("is1", "/etc/nginx/GeoIP.dat").is_some());
+    }
+
+    #[tokio::test]
+    async fn glob_matches_are_filtered_by_include_and_exclude() {
+        let config = config(concat!(
+            "  - path: /etc/mysql/*.cnf\n",
+            "    exclude: [debian.cnf]\n",
+            "  - path: /home/isucon/webapp/*\n",
+            "    include: [\"*.sh\"]\n",
+            "    exclude: [node]\n",
+        ));
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        remote.insert("is1", "/etc/mysql/my.cnf", b"m".to_vec());
+        remote.insert("is1", "/etc/mysql/debian.cnf", b"d".to_vec());
+        remote.insert("is1", "/home/isucon/webapp/go/env.sh", b"e".to_vec());
+        remote.insert("is1", "/home/isucon/webapp/go/main.go", b"g".to_vec());
+        remote.insert("is1", "/home/isucon/webapp/node/env.sh", b"e".to_vec());
+        remote.insert("is1", "/home/isucon/webapp/README", b"r".to_vec());
+        remote.insert(
+            "is1",
+            Path::new(OsStr::from_bytes(b"/etc/mysql/\xff.cnf")),
+            b"x".to_vec(),
+        );
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        let paths = plan
+            .transfers()
+            .map(|action| {
+                join_path(Path::new(&action.target.path), &action.relative_path)
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec!["/etc/mysql/my.cnf", "/home/isucon/webapp/go/env.sh"]
+        );
+        assert_eq!(plan.warnings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pull_creates_a_link_for_a_symlink() {
+        let config = config("  - path: /etc/nginx\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        remote.insert("is1", "/etc/nginx/sites-available/default", b"a".to_vec());
+        remote.insert_link(
+            "is1",
+            "/etc/nginx/sites-enabled/default",
+            "/etc/nginx/sites-available/default",
+        );
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(
+            local.link_target("is1", "/etc/nginx/sites-enabled/default"),
+            Some(PathBuf::from("/etc/nginx/sites-available/default"))
+        );
+        assert_eq!(
+            local.file("is1", "/etc/nginx/sites-available/default"),
+            Some(b"a".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn push_replaces_a_file_with_a_link() {
+        let config = config("  - path: /etc/nginx\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert_link("is1", "/etc/nginx/sites-enabled/default", "../default");
+        remote.insert("is1", "/etc/nginx/sites-enabled/default", b"a".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::Update, "is1", "sites-enabled/default"),
+            ]
+        );
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(
+            remote.link_target("is1", "/etc/nginx/sites-enabled/default"),
+            Some(PathBuf::from("../default"))
+        );
+    }
+
+    #[tokio::test]
+    async fn follow_symlinks_transfers_the_content() {
+        let config = config("  - path: /etc/nginx\n    follow_symlinks: true\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/nginx/sites-available/default", b"a".to_vec());
+        local.insert_link(
+            "is1",
+            "/etc/nginx/sites-enabled/default",
+            "../sites-available/default",
+        );
+        local.insert_link("is1", "/etc/nginx/sites-enabled/dangling", "../missing");
+        remote.insert("is1", "/etc/nginx/sites-enabled/dangling", b"b".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, true).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::NotExists, "is2", ""),
+                (SyncActionKind::Create, "is1", "sites-available/default"),
+                (SyncActionKind::Unreadable, "is1", "sites-enabled/dangling"),
+                (SyncActionKind::Create, "is1", "sites-enabled/default"),
+            ]
+        );
+        engine.execute(&plan, |_| Ok(())).await.unwrap();
+        assert_eq!(
+            remote.file("is1", "/etc/nginx/sites-enabled/default"),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(
+            remote.link_target("is1", "/etc/nginx/sites-enabled/default"),
+            None
+        );
+        assert_eq!(
+            remote.file("is1", "/etc/nginx/sites-enabled/dangling"),
+            Some(b"b".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_target_is_pulled_once_and_pushed_to_every_server() {
+        let config = config("  - path: /etc/hosts\n    shared: true\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        remote.insert("is1", "/etc/hosts", b"1".to_vec());
+        remote.insert("is2", "/etc/hosts", b"2".to_vec());
+
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(kinds(&plan), vec![(SyncActionKind::Create, "is1", "")]);
+
+        // The memory store is not shared between servers, so the local file is given to both.
+        local.insert("is1", "/etc/hosts", b"1".to_vec());
+        local.insert("is2", "/etc/hosts", b"1".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::Synced, "is1", ""),
+                (SyncActionKind::Update, "is2", ""),
+            ]
+        );
+        assert!(!plan.actions[0].is_source_hidden);
+        assert!(plan.actions[1].is_source_hidden);
+    }
+
+    fn roles_config(targets: &str) -> CliConfig {
+        let yaml = format!(
+            r#"
+remote:
+  servers:
+    - host: is1
+      roles: [app]
+    - host: is2
+      roles: [db]
+    - host: is3
+      roles: [db]
+local:
+  config_root_path: ./config
+targets:
+{}"#,
+            targets
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn role_target_applies_to_the_servers_with_the_role() {
+        let config = roles_config("  - path: /etc/mysql\n    roles: [db]\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        for server_name in ["is1", "is2", "is3"] {
+            remote.insert(server_name, "/etc/mysql/my.cnf", b"a".to_vec());
+        }
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::Create, "is2", "my.cnf"),
+                (SyncActionKind::Create, "is3", "my.cnf"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_role_target_is_selected_by_its_role_directory() {
+        let config = roles_config("  - path: /etc/mysql\n    roles: [db]\n    shared: true\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        remote.insert("is2", "/etc/mysql/my.cnf", b"a".to_vec());
+        remote.insert("is3", "/etc/mysql/my.cnf", b"b".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Pull, &local, &remote);
+
+        let plan = engine
+            .plan(Some("./config/roles/db/etc/mysql"), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::Create, "is2", "my.cnf"),
+                (SyncActionKind::NotApplicable, "is1", ""),
+            ]
+        );
+        let plan = engine
+            .plan(Some("./config/roles/app/etc/mysql"), false)
+            .await
+            .unwrap();
+        assert!(plan.actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_target_is_skipped() {
+        let config = config("  - path: /etc/hosts\n    push: false\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is1", "/etc/hosts", b"a".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::Skip, "is1", ""),
+                (SyncActionKind::Skip, "is2", ""),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn explicit_path_reports_servers_outside_the_target() {
+        let config = config("  - path: /etc/mysql\n    servers: [is2]\n");
+        let local = MemoryConfigClient::new();
+        let remote = MemoryConfigClient::new();
+        local.insert("is2", "/etc/mysql/my.cnf", b"a".to_vec());
+        let engine = SyncEngine::new(&config, SyncDirection::Push, &local, &remote);
+
+        let plan = engine.plan(None, false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![(SyncActionKind::Create, "is2", "my.cnf")]
+        );
+
+        let plan = engine.plan(Some("/etc/mysql"), false).await.unwrap();
+        assert_eq!(
+            kinds(&plan),
+            vec![
+                (SyncActionKind::Create, "is2", "my.cnf"),
+                (SyncActionKind::NotApplicable, "is1", ""),
+            ]
+        );
+    }
+}
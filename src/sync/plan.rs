@@ -0,0 +1,87 @@
+use crate::client::FileMetadata;
+use crate::config::TargetConfig;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Local to remote.
+    Push,
+    /// Remote to local.
+    Pull,
+}
+
+impl SyncDirection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncDirection::Push => "push",
+            SyncDirection::Pull => "pull",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncActionKind {
+    /// The target is disabled for this direction.
+    Skip,
+    /// The target is not for the server, but its path was selected explicitly.
+    NotApplicable,
+    /// The source file exceeds `max_file_size`.
+    TooLarge,
+    /// The source file cannot be read.
+    Unreadable,
+    /// The target has no files on the source side.
+    NotExists,
+    /// The source and destination files are identical.
+    Synced,
+    /// The file only exists on the source side.
+    Create,
+    /// The source and destination files differ.
+    Update,
+    /// The files are identical but the destination metadata differs.
+    UpdateMetadata,
+    /// The file only exists on the destination side and is deleted.
+    Delete,
+}
+
+impl SyncActionKind {
+    /// Whether executing the action writes to the destination.
+    pub fn is_transfer(&self) -> bool {
+        matches!(
+            self,
+            SyncActionKind::Create
+                | SyncActionKind::Update
+                | SyncActionKind::UpdateMetadata
+                | SyncActionKind::Delete
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncAction {
+    pub kind: SyncActionKind,
+    pub server_name: String,
+    pub target: TargetConfig,
+    pub relative_path: PathBuf,
+    /// Metadata the destination file should have.
+    pub metadata: FileMetadata,
+    /// Target of the symlink to create instead of copying the content.
+    pub link_target: Option<PathBuf>,
+    /// The source of a shared target is the same for every server, so it is reported once.
+    pub is_source_hidden: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncPlan {
+    pub direction: SyncDirection,
+    pub actions: Vec<SyncAction>,
+    /// Problems that did not stop the planning, such as skipped paths.
+    pub warnings: Vec<String>,
+}
+
+impl SyncPlan {
+    pub fn transfers(&self) -> impl Iterator<Item = &SyncAction> {
+        self.actions
+            .iter()
+            .filter(|action| action.kind.is_transfer())
+    }
+}